@@ -1,9 +1,5 @@
 #!/bin/sh
 
-# Allows Ctrl-C, by letting this sh process act as PID 1
-exit_func() {
-    exit 1
-}
-trap exit_func TERM INT
-
-"$@"
+# Replace this sh process so the bot is PID 1 and
+# gets SIGTERM from docker stop to shut down cleanly
+exec "$@"
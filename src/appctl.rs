@@ -1,27 +1,137 @@
-use std::sync::{Mutex, atomic::{AtomicBool, Ordering}};
+use crate::models::ModelInfo;
+use crossbeam_channel::{unbounded, Receiver, Sender};
 use futures::future::AbortHandle;
-use std::collections::HashMap;
-use bus::{Bus, BusReader};
+use log::*;
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc, Mutex,
+};
+use std::time::{Duration, Instant};
 
-pub struct AppCtl{
+/// Where something was said to the bot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Frontend {
+    Console,
+    Telegram,
+}
+
+/// The part that writes replies
+pub const CONVERSATION: &str = "conversation";
+/// The part that picks pictures for replies
+pub const WORD_IMAGES: &str = "word images";
+
+/// Who asked for something so the answer can find its way back
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Origin {
+    /// Unique for each request
+    pub request: u64,
+    pub frontend: Frontend,
+    /// The conversation it belongs to
+    pub session: String,
+}
+
+/// Everything sent between the parts of the bot
+///
+/// Answers carry the origin of the request that caused them
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// Something said to the bot
+    Said { origin: Origin, text: String },
+    /// The bot's reply with its journal entry
+    Reply {
+        origin: Origin,
+        text: String,
+        id: Option<u64>,
+    },
+    /// The picture for a reply, sent after every reply
+    Picture {
+        origin: Origin,
+        path: Option<PathBuf>,
+    },
+    /// Positive for a good reply and negative for a bad one
+    Rate { origin: Origin, id: u64, rating: i8 },
+    /// Asks for another reply in place of this one
+    Regenerate { origin: Origin, id: u64 },
+    /// The new reply or None if it couldn't be made
+    Regenerated {
+        origin: Origin,
+        id: u64,
+        text: Option<String>,
+    },
+}
+
+impl Event {
+    /// Answers to a request that was let in, these are never dropped
+    pub fn is_answer(&self) -> bool {
+        matches!(
+            self,
+            Event::Reply { .. } | Event::Picture { .. } | Event::Regenerated { .. }
+        )
+    }
+}
+
+/// How much of a conversation is held, for `/status`
+#[derive(Debug, Clone, Default)]
+pub struct SessionStatus {
+    /// Turns in the context including the profile seed turns
+    pub context_turns: usize,
+    pub context_tokens: usize,
+    pub journal_entries: usize,
+}
+
+/// The queue of events for one part of the bot
+struct Listener {
+    name: &'static str,
+    sender: Sender<Event>,
+    /// Requests are turned away while this one is full
+    serves_requests: bool,
+    lagging: bool,
+    /// Events dropped since it last kept up
+    dropped: usize,
+}
+
+/// Returned when a request is turned away
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Busy;
+
+pub struct AppCtl {
     alive: AtomicBool,
     abort_handles: Mutex<HashMap<&'static str, AbortHandle>>,
     images: AtomicBool,
-    me_channel: Mutex<Bus<String>>,
-    bot_channel: Mutex<Bus<String>>,
-    bot_pic_channel: Mutex<Bus<Option<PathBuf>>>,
+    next_request: AtomicU64,
+    queue_size: usize,
+    listeners: Mutex<Vec<Listener>>,
+    finished: Mutex<HashSet<&'static str>>,
+    models: Mutex<Vec<Arc<Mutex<ModelInfo>>>>,
+    started: Instant,
+    config_path: String,
+    sessions: Mutex<HashMap<String, SessionStatus>>,
+    /// How many replies were generated and how long they took in total
+    generations: Mutex<(u32, Duration)>,
+    /// Held while journals and profiles are written
+    saving: Arc<Mutex<()>>,
 }
 
 impl AppCtl {
-    pub fn new() -> Self {
+    /// Each listener can fall `queue_size` events behind
+    /// before events for it are dropped
+    pub fn new(queue_size: usize, config_path: &str) -> Self {
         Self {
             alive: AtomicBool::new(true),
             abort_handles: Mutex::new(Default::default()),
             images: AtomicBool::new(false),
-            me_channel: Mutex::new(Bus::new(1000)),
-            bot_channel: Mutex::new(Bus::new(1000)),
-            bot_pic_channel: Mutex::new(Bus::new(1000)),
+            next_request: AtomicU64::new(0),
+            queue_size,
+            listeners: Mutex::new(vec![]),
+            finished: Mutex::new(Default::default()),
+            models: Mutex::new(vec![]),
+            started: Instant::now(),
+            config_path: config_path.to_string(),
+            sessions: Mutex::new(Default::default()),
+            generations: Mutex::new(Default::default()),
+            saving: Default::default(),
         }
     }
 
@@ -29,6 +139,8 @@ impl AppCtl {
         self.alive.load(Ordering::Relaxed)
     }
 
+    /// Stops taking input, each part finishes what
+    /// it is doing then leaves
     pub fn stop(&self) {
         self.alive.store(false, Ordering::Relaxed);
         let abort_handles = &(*self.abort_handles.lock().unwrap());
@@ -37,6 +149,18 @@ impl AppCtl {
         }
     }
 
+    /// Marks a part of the bot as done shutting down
+    ///
+    /// Parts that send it events wait for this before leaving
+    pub fn finish(&self, name: &'static str) {
+        debug!("{} finished", name);
+        self.finished.lock().unwrap().insert(name);
+    }
+
+    pub fn is_finished(&self, name: &str) -> bool {
+        self.finished.lock().unwrap().contains(name)
+    }
+
     pub fn add_abortable(&self, tag: &'static str, handle: AbortHandle) {
         let mut abort_handles = self.abort_handles.lock().unwrap();
         abort_handles.remove(tag);
@@ -50,33 +174,168 @@ impl AppCtl {
         self.images.load(Ordering::Relaxed)
     }
 
-    pub fn broadcast_me_channel(&self, message: &str) {
-        let mut me_channel = self.me_channel.lock().unwrap();
-        me_channel.broadcast(message.to_string());
+    /// Keeps the info of a model so its state can be reported
+    pub fn track_model(&self, info: ModelInfo) -> Arc<Mutex<ModelInfo>> {
+        let info = Arc::new(Mutex::new(info));
+        self.models.lock().unwrap().push(info.clone());
+        info
+    }
+
+    /// The info of every model, loaded or not
+    pub fn models(&self) -> Vec<ModelInfo> {
+        let models = self.models.lock().unwrap();
+        models
+            .iter()
+            .map(|info| info.lock().unwrap().clone())
+            .collect()
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn config_path(&self) -> &str {
+        &self.config_path
     }
 
-    pub fn listen_me_channel(&self) -> BusReader<String> {
-        let mut me_channel = self.me_channel.lock().unwrap();
-        me_channel.add_rx()
+    pub fn update_session(&self, session: &str, status: SessionStatus) {
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(session.to_string(), status);
     }
 
-    pub fn broadcast_bot_channel(&self, message: &str) {
-        let mut bot_channel = self.bot_channel.lock().unwrap();
-        bot_channel.broadcast(message.to_string());
+    /// Every open conversation sorted by name
+    pub fn sessions(&self) -> Vec<(String, SessionStatus)> {
+        let mut sessions: Vec<_> = self.sessions.lock().unwrap().clone().into_iter().collect();
+        sessions.sort_by(|a, b| a.0.cmp(&b.0));
+        sessions
+    }
+
+    /// Notes how long a reply took to generate
+    pub fn record_generation(&self, took: Duration) {
+        let mut generations = self.generations.lock().unwrap();
+        generations.0 += 1;
+        generations.1 += took;
+    }
+
+    /// The lock to hold while writing files that must not be cut short
+    pub fn save_lock(&self) -> Arc<Mutex<()>> {
+        self.saving.clone()
+    }
+
+    /// Leaves straight away once nothing is being saved
+    pub fn exit(&self, code: i32) -> ! {
+        let _saving = self.saving.lock();
+        std::process::exit(code)
+    }
+
+    /// The average time to generate a reply and how many there were
+    pub fn average_generation(&self) -> Option<(Duration, u32)> {
+        let (count, total) = *self.generations.lock().unwrap();
+        if count > 0 {
+            Some((total / count, count))
+        } else {
+            None
+        }
+    }
+
+    /// Starts a new request from a frontend
+    pub fn new_origin(&self, frontend: Frontend, session: &str) -> Origin {
+        Origin {
+            request: self.next_request.fetch_add(1, Ordering::Relaxed),
+            frontend,
+            session: session.to_string(),
+        }
+    }
+
+    /// Sends the event to every listener without waiting
+    ///
+    /// A listener whose queue is full misses the event unless it
+    /// is an answer, requests are only let in while the parts that
+    /// answer them keep up so answers can't pile up for ever
+    pub fn broadcast(&self, event: Event) {
+        let mut listeners = self.listeners.lock().unwrap();
+        self.send_all(&mut listeners, event);
+    }
+
+    /// Sends a new request unless something that answers
+    /// requests is too far behind to take it
+    pub fn request(&self, event: Event) -> Result<(), Busy> {
+        if !self.is_alive() {
+            return Err(Busy);
+        }
+        let mut listeners = self.listeners.lock().unwrap();
+        if let Some(listener) = listeners
+            .iter()
+            .find(|l| l.serves_requests && self.is_full(l))
+        {
+            warn!("Turned away a request because {} is lagging", listener.name);
+            return Err(Busy);
+        }
+        self.send_all(&mut listeners, event);
+        Ok(())
+    }
+
+    fn is_full(&self, listener: &Listener) -> bool {
+        listener.sender.len() >= self.queue_size
+    }
+
+    fn send_all(&self, listeners: &mut Vec<Listener>, event: Event) {
+        let mut stopped = vec![];
+        for (i, listener) in listeners.iter_mut().enumerate() {
+            if self.is_full(listener) {
+                if !listener.lagging {
+                    warn!(
+                        "{} is lagging, dropping all but answers until it catches up",
+                        listener.name
+                    );
+                    listener.lagging = true;
+                }
+                if !event.is_answer() {
+                    listener.dropped += 1;
+                    continue;
+                }
+            } else if listener.lagging {
+                info!(
+                    "{} caught up after missing {} events",
+                    listener.name, listener.dropped
+                );
+                listener.lagging = false;
+                listener.dropped = 0;
+            }
+            if listener.sender.send(event.clone()).is_err() {
+                debug!("{} stopped listening", listener.name);
+                stopped.push(i);
+            }
+        }
+        for i in stopped.into_iter().rev() {
+            listeners.remove(i);
+        }
     }
 
-    pub fn listen_bot_channel(&self) -> BusReader<String> {
-        let mut bot_channel = self.bot_channel.lock().unwrap();
-        bot_channel.add_rx()
+    /// Gets every event from now on, `name` is used when it lags
+    pub fn listen(&self, name: &'static str) -> Receiver<Event> {
+        self.add_listener(name, false)
     }
 
-    pub fn broadcast_bot_pic_channel(&self, message: Option<PathBuf>) {
-        let mut bot_pic_channel = self.bot_pic_channel.lock().unwrap();
-        bot_pic_channel.broadcast(message);
+    /// Like listen but for the parts that answer requests, new
+    /// requests are turned away while this one is behind
+    pub fn serve(&self, name: &'static str) -> Receiver<Event> {
+        self.add_listener(name, true)
     }
 
-    pub fn listen_bot_pic_channel(&self) -> BusReader<Option<PathBuf>> {
-        let mut bot_pic_channel = self.bot_pic_channel.lock().unwrap();
-        bot_pic_channel.add_rx()
+    fn add_listener(&self, name: &'static str, serves_requests: bool) -> Receiver<Event> {
+        // Only answers go past queue_size, see broadcast
+        let (sender, receiver) = unbounded();
+        let mut listeners = self.listeners.lock().unwrap();
+        listeners.push(Listener {
+            name,
+            sender,
+            serves_requests,
+            lagging: false,
+            dropped: 0,
+        });
+        receiver
     }
 }
@@ -0,0 +1,60 @@
+use rand::Rng;
+use std::time::{Duration, Instant};
+
+/// Exponential backoff with jitter for retrying telegram
+pub struct Backoff {
+    attempts: u32,
+    base: Duration,
+    max: Duration,
+    next_attempt: Option<Instant>,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self {
+            attempts: 0,
+            base,
+            max,
+            next_attempt: None,
+        }
+    }
+
+    /// Number of failures since the last success
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+        self.next_attempt = None;
+    }
+
+    /// Records a failure and returns how long to wait
+    ///
+    /// The delay doubles on each failure up to the max with
+    /// up to half of it added at random so retries spread out
+    pub fn fail(&mut self) -> Duration {
+        let exponent = self.attempts.min(16);
+        self.attempts = self.attempts.saturating_add(1);
+        let delay = std::cmp::min(self.base * 2u32.pow(exponent), self.max);
+        let jitter_ms = rand::thread_rng().gen_range(0..=delay.as_millis() as u64 / 2);
+        let delay = delay + Duration::from_millis(jitter_ms);
+        self.next_attempt = Some(Instant::now() + delay);
+        delay
+    }
+
+    /// Records a failure where the wait is already known, like
+    /// when telegram says how long to wait
+    pub fn fail_for(&mut self, delay: Duration) -> Duration {
+        self.attempts = self.attempts.saturating_add(1);
+        self.next_attempt = Some(Instant::now() + delay);
+        delay
+    }
+
+    /// True once the delay from the last failure has passed
+    pub fn ready(&self) -> bool {
+        self.next_attempt
+            .map(|next_attempt| Instant::now() >= next_attempt)
+            .unwrap_or(true)
+    }
+}
@@ -58,13 +58,50 @@ impl Classy {
             .pop()
     }
 
+    /// Scores each label against the input
+    ///
+    /// The template turns a label into the hypothesis
+    /// e.g. `"This message is about {}."`
     pub fn classify_with_lables(
         &self,
         input: &str,
         candidate_labels: &[&str],
+        template: Option<&str>,
+        max_length: usize,
     ) -> Option<Vec<Label>> {
+        let template = template.map(|template| {
+            let template = template.to_string();
+            Box::new(move |label: &str| template.replace("{}", label))
+                as Box<dyn Fn(&str) -> String>
+        });
         self.model
-            .predict_multilabel([input], candidate_labels, None, 128)
+            .predict_multilabel([input], candidate_labels, template, max_length)
             .pop()
     }
+
+    /// Scores the labels a chunk at a time so that each
+    /// pass of the model stays small
+    ///
+    /// A `chunk_size` of zero scores every label at once
+    pub fn classify_in_chunks(
+        &self,
+        input: &str,
+        candidate_labels: &[&str],
+        template: Option<&str>,
+        max_length: usize,
+        chunk_size: usize,
+    ) -> Vec<Label> {
+        let chunk_size = if chunk_size == 0 {
+            candidate_labels.len().max(1)
+        } else {
+            chunk_size
+        };
+        candidate_labels
+            .chunks(chunk_size)
+            .flat_map(|chunk| {
+                self.classify_with_lables(input, chunk, template, max_length)
+                    .unwrap_or_default()
+            })
+            .collect()
+    }
 }
@@ -0,0 +1,184 @@
+use crate::appctl::AppCtl;
+
+use std::time::Duration;
+
+use log::*;
+
+/// Commands understood by the bot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Start,
+    Help,
+    Stop,
+    Cancel,
+    YesImages,
+    NoImages,
+    Remember,
+    Status,
+}
+
+/// Every command with its name and what it does
+///
+/// This is where `/help` and the telegram command menu come from
+pub const COMMANDS: &[(Command, &str, &str)] = &[
+    (Command::Help, "help", "Show what the bot understands"),
+    (Command::Stop, "stop", "Shut the bot down"),
+    (
+        Command::Cancel,
+        "cancel",
+        "Don't send replies still being written",
+    ),
+    (
+        Command::YesImages,
+        "yesimages",
+        "Send pictures with replies",
+    ),
+    (Command::NoImages, "noimages", "Stop sending pictures"),
+    (
+        Command::Remember,
+        "remember",
+        "Remember a fact with key=value",
+    ),
+    (Command::Status, "status", "Show what the bot is doing"),
+    (Command::Start, "start", "Start chatting"),
+];
+
+impl Command {
+    /// Finds the command at the start of the input and the rest of it
+    ///
+    /// Returns None if the input is not a known command
+    pub fn parse(input: &str) -> Option<(Self, &str)> {
+        let input = input.trim().strip_prefix('/')?;
+        let (name, args) = match input.find(char::is_whitespace) {
+            Some(i) => (&input[..i], input[i..].trim()),
+            None => (input, ""),
+        };
+        // Telegram adds the bot's username to commands in groups
+        let name = name.split('@').next().unwrap_or(name);
+        COMMANDS
+            .iter()
+            .find(|(_, command_name, _)| name.eq_ignore_ascii_case(command_name))
+            .map(|(command, ..)| (*command, args))
+    }
+}
+
+/// Lists every command with what it does
+pub fn help_text() -> String {
+    COMMANDS
+        .iter()
+        .map(|(_, name, description)| format!("/{} - {}", name, description))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Hours, minutes and seconds
+fn duration_text(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    format!(
+        "{}h {}m {}s",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// Describes what the bot is doing
+pub fn status_text(appctl: &AppCtl) -> String {
+    let mut lines = vec![
+        format!("Up for {}", duration_text(appctl.uptime())),
+        format!("Config: {}", appctl.config_path()),
+        format!(
+            "Images: {}",
+            if appctl.images_enabled() { "on" } else { "off" }
+        ),
+    ];
+    match appctl.average_generation() {
+        Some((average, count)) => lines.push(format!(
+            "Replies take {:.1}s on average over {}",
+            average.as_secs_f64(),
+            count
+        )),
+        None => lines.push("No replies yet".to_string()),
+    }
+
+    let models = appctl.models();
+    if models.is_empty() {
+        lines.push("Models: None used yet".to_string());
+    } else {
+        lines.push("Models:".to_string());
+    }
+    for model in models {
+        let state = match (model.loaded, model.last_used) {
+            (true, Some(last_used)) => {
+                format!("loaded, used {}s ago", last_used.elapsed().as_secs())
+            }
+            (true, None) => "loaded".to_string(),
+            (false, _) => "not loaded".to_string(),
+        };
+        lines.push(format!("  {} from {}: {}", model.name, model.path, state));
+    }
+
+    let sessions = appctl.sessions();
+    if !sessions.is_empty() {
+        lines.push("Conversations:".to_string());
+    }
+    for (session, status) in sessions {
+        lines.push(format!(
+            "  {}: {} turns ({} tokens) in context, {} in the journal",
+            session, status.context_turns, status.context_tokens, status.journal_entries
+        ));
+    }
+    lines.join("\n")
+}
+
+/// Sent instead of a reply when the bot is too far behind to take more
+pub const BUSY_REPLY: &str = "...Busy, try again in a moment...";
+
+/// Sent to anyone still waiting for a reply when the bot stops
+pub const SHUTDOWN_REPLY: &str = "...Going to sleep, ask me again later...";
+
+/// Sent when a reply was asked for but never came
+pub const TIMEOUT_REPLY: &str = "...That took too long, try again...";
+
+/// What a frontend has to do with its input after any
+/// command in it has run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Handled {
+    /// Send this reply
+    Reply(String),
+    /// Send this reply then shut down
+    Shutdown(String),
+    /// Send this reply and don't send replies still being written
+    Cancel(String),
+    /// Pass the input on to the conversation
+    Say,
+}
+
+/// Runs any command in the input
+///
+/// The same commands work the same way in every frontend, anything
+/// that only a frontend can do is left to it through the result
+pub fn handle(appctl: &AppCtl, input: &str) -> Handled {
+    match Command::parse(input) {
+        Some((Command::NoImages, _)) => {
+            appctl.enable_images(false);
+            Handled::Reply("...Images Off...".to_string())
+        }
+        Some((Command::YesImages, _)) => {
+            appctl.enable_images(true);
+            Handled::Reply("...Images On...".to_string())
+        }
+        Some((Command::Stop, _)) => Handled::Shutdown("...Leaving...".to_string()),
+        Some((Command::Cancel, _)) => Handled::Cancel("...Never mind...".to_string()),
+        Some((Command::Start, _)) => Handled::Reply("Waiting for you to say something".to_string()),
+        Some((Command::Help, _)) => Handled::Reply(help_text()),
+        Some((Command::Status, _)) => Handled::Reply(status_text(appctl)),
+        // Remembering is done by the conversation
+        Some((Command::Remember, _)) => Handled::Say,
+        None if input.trim().starts_with('/') => {
+            debug!("Got unknown command {}", input.trim());
+            Handled::Reply("...I don't know that one, try /help...".to_string())
+        }
+        None => Handled::Say,
+    }
+}
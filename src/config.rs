@@ -3,7 +3,11 @@ use serde::Deserialize;
 use validator::{Validate, ValidationError};
 use validator_derive::Validate;
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// File extensions picked up when a word image path is a directory or glob
+const IMAGE_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "gif", "webp", "bmp"];
 
 #[derive(Debug, Deserialize, Validate, Clone)]
 pub struct Config {
@@ -13,6 +17,10 @@ pub struct Config {
     #[validate(custom = "ensure_classify_model_files")]
     pub classify_model_name: String,
 
+    #[serde(default = "default_model_name")]
+    #[validate(custom = "ensure_embed_model_files")]
+    pub embed_model_name: String,
+
     #[serde(default = "default_max_context")]
     pub max_context: usize,
 
@@ -29,11 +37,58 @@ pub struct Config {
     #[serde(default)]
     pub telegram_id: Option<i64>,
 
+    #[serde(default)]
+    #[validate]
+    pub telegram_webhook: Option<WebhookConfig>,
+
+    #[serde(default)]
+    pub telegram_groups: Vec<i64>,
+
+    #[serde(default = "default_telegram_max_retries")]
+    pub telegram_max_retries: u32,
+
+    #[serde(default)]
+    pub telegram_format: TelegramFormat,
+
+    #[serde(default)]
+    pub console: Option<bool>,
+
+    #[serde(default)]
+    pub console_mirror: bool,
+
+    #[serde(default = "default_queue_size")]
+    #[validate(range(min = 1))]
+    pub queue_size: usize,
+
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+
+    #[serde(default)]
+    pub model_idle_timeout: u64,
+
     #[serde(default = "default_bot_name")]
     pub bot_name: String,
 
     #[serde(default = "default_summary")]
     pub do_summary: bool,
+
+    #[serde(default = "default_learn_profile")]
+    pub learn_profile: bool,
+}
+
+impl Config {
+    /// How long a model sits unused before it is unloaded,
+    /// None keeps them loaded
+    pub fn model_idle(&self) -> Option<Duration> {
+        match self.model_idle_timeout {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds)),
+        }
+    }
+}
+
+fn default_learn_profile() -> bool {
+    false
 }
 
 fn default_summary() -> bool {
@@ -92,21 +147,328 @@ fn ensure_classify_model_files(model_name: &str) -> Result<(), ValidationError>
     }
 }
 
+fn ensure_embed_model_files(model_name: &str) -> Result<(), ValidationError> {
+    if model_name == "default" {
+        Ok(())
+    } else if !PathBuf::from(format!("./{}.model/modules.json", model_name)).exists() {
+        Err(ValidationError::new("Sentence embeddings modules missing"))
+    } else if !PathBuf::from(format!("./{}.model/config.json", model_name)).exists() {
+        Err(ValidationError::new("Config model missing"))
+    } else {
+        Ok(())
+    }
+}
+
+fn default_queue_size() -> usize {
+    100
+}
+
+/// Seconds, less than docker's ten so it doesn't get killed first
+fn default_shutdown_timeout() -> u64 {
+    8
+}
+
+fn default_model_name() -> String {
+    "default".to_string()
+}
+
 fn default_bot_name() -> String {
     "Holly".to_string()
 }
 
 #[derive(Debug, Deserialize, Validate, Clone)]
+pub struct WebhookConfig {
+    #[serde(default = "default_webhook_address")]
+    #[validate(custom = "ensure_socket_address")]
+    pub address: String,
+
+    #[serde(default = "default_webhook_path")]
+    #[validate(custom = "ensure_webhook_path")]
+    pub path: String,
+
+    #[serde(default)]
+    pub secret_token: Option<String>,
+
+    #[serde(default)]
+    #[validate(url)]
+    pub url: Option<String>,
+}
+
+fn default_telegram_max_retries() -> u32 {
+    5
+}
+
+fn default_webhook_address() -> String {
+    "0.0.0.0:8443".to_string()
+}
+
+fn default_webhook_path() -> String {
+    "/telegram".to_string()
+}
+
+fn ensure_socket_address(address: &str) -> Result<(), ValidationError> {
+    if address.parse::<std::net::SocketAddr>().is_ok() {
+        Ok(())
+    } else {
+        Err(ValidationError::new("Webhook address must be ip:port"))
+    }
+}
+
+fn ensure_webhook_path(path: &str) -> Result<(), ValidationError> {
+    if path.starts_with('/') {
+        Ok(())
+    } else {
+        Err(ValidationError::new("Webhook path must start with /"))
+    }
+}
+
+#[derive(Debug, Deserialize, Validate, Clone)]
+#[validate(schema(function = "ensure_word_thresholds"))]
 pub struct WordImagesConfig {
+    #[serde(default = "default_image_threshold")]
+    #[validate(range(min = 0.0, max = 1.0))]
+    pub threshold: f64,
+
+    #[serde(default)]
+    #[validate(custom = "ensure_hypothesis_template")]
+    pub hypothesis_template: Option<String>,
+
+    #[serde(default = "default_classify_max_length")]
+    #[validate(range(min = 1))]
+    pub max_length: usize,
+
+    #[serde(default)]
+    pub classify: ImageSource,
+
+    #[serde(default)]
+    pub matcher: ImageMatcher,
+
+    #[serde(default)]
+    pub selection: ImageSelection,
+
+    #[serde(default = "default_similarity_threshold")]
+    #[validate(range(min = -1.0, max = 1.0))]
+    pub similarity_threshold: f64,
+
+    #[serde(default)]
+    pub min_turns: usize,
+
+    #[serde(default)]
+    pub image_cooldown: usize,
+
+    #[serde(default)]
+    pub avoid_last: usize,
+
+    #[serde(default = "default_label_batch")]
+    pub label_batch: usize,
+
+    #[serde(default = "default_cache_size")]
+    #[validate(range(min = 1))]
+    pub cache_size: usize,
+
     #[serde(default)]
     #[validate]
     pub word_images: Vec<WordImageData>,
 }
 
+impl WordImagesConfig {
+    /// Finds the image files for every word image
+    pub fn expand_images(&mut self) {
+        for word_image in self.word_images.iter_mut() {
+            word_image.images = image_files(&word_image.path);
+        }
+    }
+}
+
+fn default_image_threshold() -> f64 {
+    0.96
+}
+
+fn default_classify_max_length() -> usize {
+    128
+}
+
+fn default_similarity_threshold() -> f64 {
+    0.5
+}
+
+fn default_label_batch() -> usize {
+    32
+}
+
+fn default_cache_size() -> usize {
+    64
+}
+
+/// Zero shot scores are probabilities while embeddings are
+/// compared by cosine similarity which can go below zero
+fn ensure_word_thresholds(config: &WordImagesConfig) -> Result<(), ValidationError> {
+    let min = match config.matcher {
+        ImageMatcher::ZeroShot => 0.0,
+        ImageMatcher::Embeddings => -1.0,
+    };
+    if config
+        .word_images
+        .iter()
+        .filter_map(|word_image| word_image.threshold)
+        .all(|threshold| threshold >= min)
+    {
+        Ok(())
+    } else {
+        Err(ValidationError::new(
+            "Word image threshold must be between 0 and 1 with zero_shot",
+        ))
+    }
+}
+
+fn ensure_hypothesis_template(template: &str) -> Result<(), ValidationError> {
+    if template.contains("{}") {
+        Ok(())
+    } else {
+        Err(ValidationError::new(
+            "Hypothesis template must contain {} for the label",
+        ))
+    }
+}
+
 #[derive(Debug, Deserialize, Validate, Clone)]
 pub struct WordImageData {
+    /// A single image, a directory of images or a glob pattern
     pub path: PathBuf,
 
+    /// The image files found at `path`
+    #[serde(skip)]
+    #[validate(custom = "ensure_images_found")]
+    pub images: Vec<PathBuf>,
+
     #[validate(length(min = 1))]
     pub words: Vec<String>,
+
+    #[serde(default)]
+    #[validate(range(min = -1.0, max = 1.0))]
+    pub threshold: Option<f64>,
+
+    #[serde(default)]
+    pub trigger: Option<ImageSource>,
+
+    #[serde(default)]
+    pub cooldown: Option<usize>,
+
+    #[serde(default = "default_weight")]
+    #[validate(range(min = 0.0))]
+    pub weight: f64,
+}
+
+fn default_weight() -> f64 {
+    1.0
+}
+
+fn ensure_images_found(images: &[PathBuf]) -> Result<(), ValidationError> {
+    if images.is_empty() {
+        Err(ValidationError::new("No images found at word image path"))
+    } else {
+        Ok(())
+    }
+}
+
+fn is_image_file(path: &Path) -> bool {
+    path.is_file()
+        && path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| IMAGE_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+            .unwrap_or(false)
+}
+
+/// Expands a path that is a file, a directory or a glob pattern
+pub fn image_files(path: &Path) -> Vec<PathBuf> {
+    let mut images: Vec<PathBuf> = if path.is_dir() {
+        match std::fs::read_dir(path) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|entry| is_image_file(entry))
+                .collect(),
+            Err(_) => vec![],
+        }
+    } else if path.is_file() {
+        vec![path.to_path_buf()]
+    } else {
+        match glob::glob(&path.to_string_lossy()) {
+            Ok(paths) => paths
+                .filter_map(Result::ok)
+                .filter(|entry| is_image_file(entry))
+                .collect(),
+            Err(_) => vec![],
+        }
+    };
+    images.sort();
+    images
+}
+
+/// Which side of the exchange is classified for images
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageSource {
+    /// What I said
+    Me,
+    /// What the bot replied
+    Bot,
+    /// My input and the bot reply together
+    Both,
+}
+
+impl Default for ImageSource {
+    fn default() -> Self {
+        ImageSource::Bot
+    }
+}
+
+/// How the words of the word images are matched
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageMatcher {
+    /// Zero shot classification of every word
+    ZeroShot,
+    /// Cosine similarity of sentence embeddings
+    Embeddings,
+}
+
+impl Default for ImageMatcher {
+    fn default() -> Self {
+        ImageMatcher::ZeroShot
+    }
+}
+
+/// How an image is picked from the matching word images
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageSelection {
+    /// Random with the chance given by score times weight
+    Weighted,
+    /// Always the highest score times weight
+    Best,
+}
+
+impl Default for ImageSelection {
+    fn default() -> Self {
+        ImageSelection::Weighted
+    }
+}
+
+/// How formatting in replies is shown on telegram
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TelegramFormat {
+    /// Sent as is
+    Plain,
+    /// Rendered with telegram's HTML parse mode
+    Html,
+    /// Rendered with telegram's Markdown parse mode
+    Markdown,
+}
+
+impl Default for TelegramFormat {
+    fn default() -> Self {
+        TelegramFormat::Plain
+    }
 }
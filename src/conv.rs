@@ -5,12 +5,14 @@ use rust_bert::pipelines::conversation::{
 use rust_bert::resources::LocalResource;
 use uuid::Uuid;
 
-use scopeguard::defer_on_unwind;
+use scopeguard::{defer, defer_on_unwind};
+use std::collections::HashMap;
 use std::fs;
 use std::path::PathBuf;
-use std::sync::mpsc::RecvTimeoutError;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use crossbeam_channel::RecvTimeoutError;
 use inflector::cases::{
     sentencecase::{is_sentence_case, to_sentence_case},
     snakecase::to_snake_case,
@@ -19,19 +21,54 @@ use serde::{Deserialize, Serialize};
 
 use log::*;
 
-use crate::appctl::AppCtl;
+use crate::appctl::{AppCtl, Event, SessionStatus, CONVERSATION};
+use crate::command::Command;
+use crate::enti::Enti;
+use crate::models::{model_path, Lazy};
+use crate::profile::Profile;
 use crate::sumi::Sumi;
 use crate::Error;
 use crate::RX_TIMEOUT;
 
+/// The conversation had on the console and in the private telegram chat
+pub const DEFAULT_SESSION: &str = "default";
+
+/// Where the journal of a session is kept
+fn journal_path(session: &str) -> String {
+    if session == DEFAULT_SESSION {
+        "./journal.toml".to_string()
+    } else {
+        format!("./journal-{}.toml", session)
+    }
+}
+
+/// Where the profile of a session is kept
+fn profile_path(session: &str) -> String {
+    if session == DEFAULT_SESSION {
+        "./profile.toml".to_string()
+    } else {
+        format!("./profile-{}.toml", session)
+    }
+}
+
+/// A conversation with its own journal and profile
+struct Session {
+    uuid: Uuid,
+    past: Vec<String>,
+    history: Vec<Past>,
+    profile: Profile,
+    seed_turns: usize,
+}
+
 pub struct Conv {
-    model: ConversationModel,
+    model: Lazy<ConversationModel>,
     manager: Mutex<ConversationManager>,
-    uuid: Uuid,
-    past: Mutex<Vec<String>>,
+    sessions: Mutex<HashMap<String, Session>>,
     max_context: usize,
-    history: Mutex<Vec<Past>>,
     do_summary: bool,
+    enti: Option<Lazy<Enti>>,
+    /// Keeps the bot from exiting halfway through a save
+    save_lock: Arc<Mutex<()>>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone, Copy)]
@@ -45,6 +82,8 @@ pub struct Past {
     speaker: Speaker,
     id: u64,
     message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rating: Option<i8>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -52,66 +91,234 @@ pub struct History {
     history: Vec<Past>,
 }
 
+/// The settings for the conversation model named in the config
+fn conversation_config(model_name: &str) -> ConversationConfig {
+    let mut conversation_config;
+    if model_name == "default" {
+        conversation_config = ConversationConfig::default();
+        conversation_config.min_length = 2;
+    } else {
+        conversation_config = ConversationConfig {
+            model_type: ModelType::GPT2,
+            model_resource: Box::new(LocalResource {
+                local_path: PathBuf::from(format!("./{}.model/model.ot", model_name)),
+            }),
+            config_resource: Box::new(LocalResource {
+                local_path: PathBuf::from(format!("./{}.model/config.json", model_name)),
+            }),
+            vocab_resource: Box::new(LocalResource {
+                local_path: PathBuf::from(format!("./{}.model/vocab.json", model_name)),
+            }),
+            merges_resource: Box::new(LocalResource {
+                local_path: PathBuf::from(format!("./{}.model/merges.txt", model_name)),
+            }),
+            min_length: 2,
+            max_length: 100,
+            min_length_for_response: 32,
+            do_sample: true,
+            early_stopping: false,
+            num_beams: 5,
+            temperature: 1.3,
+            top_k: 50,
+            top_p: 0.95,
+            repetition_penalty: 1.5,
+            length_penalty: 1.0,
+            no_repeat_ngram_size: 0,
+            num_return_sequences: 1,
+            diversity_penalty: None,
+            num_beam_groups: None,
+            ..Default::default()
+        };
+    }
+    conversation_config
+}
+
 impl Conv {
-    pub fn new(model_name: &str, max_context: usize) -> Self {
-        let mut conversation_config;
-        if model_name == "default" {
-            conversation_config = ConversationConfig::default();
-            conversation_config.min_length = 2;
-        } else {
-            conversation_config = ConversationConfig {
-                model_type: ModelType::GPT2,
-                model_resource: Box::new(LocalResource {
-                    local_path: PathBuf::from(format!("./{}.model/model.ot", model_name)),
-                }),
-                config_resource: Box::new(LocalResource {
-                    local_path: PathBuf::from(format!("./{}.model/config.json", model_name)),
-                }),
-                vocab_resource: Box::new(LocalResource {
-                    local_path: PathBuf::from(format!("./{}.model/vocab.json", model_name)),
-                }),
-                merges_resource: Box::new(LocalResource {
-                    local_path: PathBuf::from(format!("./{}.model/merges.txt", model_name)),
-                }),
-                min_length: 2,
-                max_length: 100,
-                min_length_for_response: 32,
-                do_sample: true,
-                early_stopping: false,
-                num_beams: 5,
-                temperature: 1.3,
-                top_k: 50,
-                top_p: 0.95,
-                repetition_penalty: 1.5,
-                length_penalty: 1.0,
-                no_repeat_ngram_size: 0,
-                num_return_sequences: 1,
-                diversity_penalty: None,
-                num_beam_groups: None,
-                ..Default::default()
-            };
+    pub fn new(
+        appctl: &AppCtl,
+        model_name: &str,
+        max_context: usize,
+        idle: Option<Duration>,
+    ) -> Self {
+        let config_name = model_name.to_string();
+        Self {
+            model: Lazy::new(
+                appctl,
+                CONVERSATION,
+                model_path(model_name),
+                idle,
+                move || {
+                    ConversationModel::new(conversation_config(&config_name))
+                        .expect("Unable to setup model")
+                },
+            ),
+            manager: Mutex::new(ConversationManager::new()),
+            sessions: Mutex::new(Default::default()),
+            max_context,
+            do_summary: false,
+            enti: None,
+            save_lock: appctl.save_lock(),
+        }
+    }
+
+    /// Drops the models that have not been used for a while
+    pub fn unload_idle(&self) {
+        self.model.unload_idle();
+        if let Some(enti) = &self.enti {
+            enti.unload_idle();
+        }
+    }
+
+    /// Loads the journal and profile of a session the first time it is used
+    pub fn open_session(&self, session: &str) {
+        if self.sessions.lock().unwrap().contains_key(session) {
+            return;
+        }
+        debug!("Opening conversation {}", session);
+        let profile = Profile::load(&profile_path(session));
+        let history = self.remember_past(&journal_path(session));
+
+        let mut conversation = Conversation::new_empty();
+        let seed_turns = self.load_context(&mut conversation, &profile, &history);
+        let uuid = self.manager.lock().unwrap().add(conversation);
+
+        self.sessions.lock().unwrap().insert(
+            session.to_string(),
+            Session {
+                uuid,
+                past: vec![],
+                history,
+                profile,
+                seed_turns,
+            },
+        );
+    }
+
+    /// How much of a session is in the context and journal
+    pub fn status(&self, session: &str) -> SessionStatus {
+        let mut conversation_manager = self.manager.lock().unwrap();
+        let sessions = self.sessions.lock().unwrap();
+        match sessions.get(session) {
+            Some(state) => {
+                let (context_turns, context_tokens) = conversation_manager
+                    .get(&state.uuid)
+                    .map(|convo| {
+                        (
+                            convo.past_user_inputs.len(),
+                            convo.history.iter().map(|turn| turn.len()).sum(),
+                        )
+                    })
+                    .unwrap_or_default();
+                SessionStatus {
+                    context_turns,
+                    context_tokens,
+                    journal_entries: state.history.len(),
+                }
+            }
+            None => Default::default(),
         }
+    }
 
-        let conversation_model =
-            ConversationModel::new(conversation_config).expect("Unable to setup model");
+    /// Names of every session opened so far
+    pub fn sessions(&self) -> Vec<String> {
+        self.sessions.lock().unwrap().keys().cloned().collect()
+    }
 
-        let conversation = Conversation::new_empty();
+    pub fn save_profile(&self, session: &str) -> Result<(), Error> {
+        let profile = match self.sessions.lock().unwrap().get(session) {
+            Some(state) => state.profile.clone(),
+            None => return Err(Error::ConversationUnknown),
+        };
+        let _saving = self.save_lock.lock();
+        profile.save(&profile_path(session))
+    }
 
-        let mut conversation_manager = ConversationManager::new();
-        let conversation_uuid = conversation_manager.add(conversation);
+    /// Adds a fact to the profile and reseeds the conversation
+    pub fn remember_fact(&self, session: &str, key: &str, value: &str) -> Result<bool, Error> {
+        let changed = match self.sessions.lock().unwrap().get_mut(session) {
+            Some(state) => state.profile.remember(key, value),
+            None => return Err(Error::ConversationUnknown),
+        };
+        if changed {
+            self.reseed(session)?;
+        }
+        Ok(changed)
+    }
 
-        Self {
-            model: conversation_model,
-            manager: Mutex::new(conversation_manager),
-            uuid: conversation_uuid,
-            past: Mutex::new(vec![]),
-            max_context,
-            history: Mutex::new(Default::default()),
-            do_summary: false,
+    /// Extracts facts from user input if an entity model is loaded
+    pub fn learn(&self, session: &str, input: &str) -> Result<bool, Error> {
+        let changed = match (
+            self.enti.as_ref(),
+            self.sessions.lock().unwrap().get_mut(session),
+        ) {
+            (Some(enti), Some(state)) => enti.with(|enti| state.profile.learn(enti, input)),
+            (None, Some(_)) => false,
+            (_, None) => return Err(Error::ConversationUnknown),
+        };
+        if changed {
+            self.reseed(session)?;
         }
+        Ok(changed)
     }
 
-    pub fn remember_past(&self, file_path: &str) -> Result<(), Error> {
+    fn reseed(&self, session: &str) -> Result<(), Error> {
+        let mut conversation_manager = self.manager.lock().unwrap();
+        let mut sessions = self.sessions.lock().unwrap();
+        let state = sessions
+            .get_mut(session)
+            .ok_or(Error::ConversationUnknown)?;
+        let conversation = conversation_manager
+            .get(&state.uuid)
+            .ok_or(Error::ConversationUnknown)?;
+        // Drops any input left by a reply that failed
+        conversation.new_user_input = None;
+        state.seed_turns = self.load_context(conversation, &state.profile, &state.history);
+        Ok(())
+    }
+
+    /// Loads the profile seed turns followed by the recent
+    /// journal into the conversation
+    ///
+    /// Returns the number of seed turns
+    fn load_context(
+        &self,
+        conversation: &mut Conversation,
+        profile: &Profile,
+        history: &[Past],
+    ) -> usize {
+        let seeds = profile.seed_turns();
+
+        let mut recent: &[Past] = if self.max_context > 0 && history.len() > self.max_context * 2 {
+            &history[history.len() - self.max_context * 2..]
+        } else {
+            history
+        };
+        // Context must start with something said by me
+        while let Some(Past {
+            speaker: Speaker::Bot,
+            ..
+        }) = recent.first()
+        {
+            recent = &recent[1..];
+        }
+
+        let history_texts: Vec<&str> = seeds
+            .iter()
+            .flat_map(|(me, bot)| vec![me.as_str(), bot.as_str()])
+            .chain(recent.iter().map(|k| k.message.as_str()))
+            .collect();
+        let history_ids = self
+            .model
+            .with(|model| model.encode_prompts(&history_texts));
+
+        conversation.past_user_inputs.clear();
+        conversation.generated_responses.clear();
+        conversation.history.clear();
+        conversation.load_from_history(&history_texts, &history_ids);
+        seeds.len()
+    }
+
+    fn remember_past(&self, file_path: &str) -> Vec<Past> {
         let history_path: PathBuf = PathBuf::from(file_path);
         let user_past_str = fs::read_to_string(&history_path).unwrap_or_else(|_| {
             info!("They do not know you yet");
@@ -121,93 +328,67 @@ impl Conv {
         let mut history_file: History =
             toml::from_str(&user_past_str).expect("Couldn't load history.");
 
-        let mut conversation_manager = self.manager.lock().unwrap();
-        if let Some(conversation) = conversation_manager.get(&self.uuid).as_mut() {
-            history_file.history.sort_unstable_by_key(|k| k.id);
-
-            if self.do_summary {
-                let sumi = Sumi::new();
-                let all_me_messages: Vec<String> = history_file
-                    .history
-                    .iter()
-                    .flat_map(|m| match m.speaker {
-                        Speaker::Me => Some(m.message.clone()),
-                        _ => None,
-                    })
-                    .collect();
-
-                let msg_start = std::cmp::max(all_me_messages.len() - 50, 0);
-                info!(
-                    "Summary Me: {}",
-                    sumi.summary(&all_me_messages[msg_start..].join("\n"))
-                        .unwrap()
-                );
-
-                let all_bot_messages: Vec<String> = history_file
-                    .history
-                    .iter()
-                    .flat_map(|m| match m.speaker {
-                        Speaker::Bot => Some(m.message.clone()),
-                        _ => None,
-                    })
-                    .collect();
-
-                let msg_start = std::cmp::max(all_bot_messages.len() - 50, 0);
-                info!(
-                    "Summary Bot: {}",
-                    sumi.summary(&all_bot_messages[msg_start..].join("\n"))
-                        .unwrap()
-                );
-
-                let all_messages: Vec<String> = history_file
-                    .history
-                    .iter()
-                    .map(|m| match m.speaker {
-                        Speaker::Me => m.message.clone(),
-                        Speaker::Bot => m.message.clone(),
-                    })
-                    .collect();
-                let msg_start = std::cmp::max(all_messages.len() - 50, 0);
-
-                info!(
-                    "Summary: {}",
-                    sumi.summary(&all_messages[msg_start..].join("\n")).unwrap()
-                );
-            }
-
-            let mut my_history = self.history.lock().unwrap();
-            for past in history_file.history {
-                match past.speaker {
-                    Speaker::Me => {
-                        conversation.past_user_inputs.push(past.message.clone());
-                    }
-                    Speaker::Bot => {
-                        conversation.generated_responses.push(past.message.clone());
-                    }
-                }
-                (*my_history).push(past.clone());
-            }
-            (*my_history).sort_unstable_by_key(|k| k.id);
-            let history_texts: Vec<&str> =
-                if self.max_context > 0 && (*my_history).len() > self.max_context * 2 {
-                    let max_range = std::cmp::min(self.max_context * 2, (*my_history).len() - 1);
-                    (*my_history)[0..max_range]
-                        .iter()
-                        .map(|k| k.message.as_str())
-                        .collect()
-                } else {
-                    (*my_history).iter().map(|k| k.message.as_str()).collect()
-                };
-            let history_ids = self.model.encode_prompts(&history_texts);
-            conversation.load_from_history(&history_texts, &history_ids);
-            Ok(())
-        } else {
-            Err(Error::ConversationUnknown)
+        history_file.history.sort_unstable_by_key(|k| k.id);
+
+        if self.do_summary {
+            let sumi = Sumi::new();
+            let all_me_messages: Vec<String> = history_file
+                .history
+                .iter()
+                .flat_map(|m| match m.speaker {
+                    Speaker::Me => Some(m.message.clone()),
+                    _ => None,
+                })
+                .collect();
+
+            let msg_start = std::cmp::max(all_me_messages.len() - 50, 0);
+            info!(
+                "Summary Me: {}",
+                sumi.summary(&all_me_messages[msg_start..].join("\n"))
+                    .unwrap()
+            );
+
+            let all_bot_messages: Vec<String> = history_file
+                .history
+                .iter()
+                .flat_map(|m| match m.speaker {
+                    Speaker::Bot => Some(m.message.clone()),
+                    _ => None,
+                })
+                .collect();
+
+            let msg_start = std::cmp::max(all_bot_messages.len() - 50, 0);
+            info!(
+                "Summary Bot: {}",
+                sumi.summary(&all_bot_messages[msg_start..].join("\n"))
+                    .unwrap()
+            );
+
+            let all_messages: Vec<String> = history_file
+                .history
+                .iter()
+                .map(|m| match m.speaker {
+                    Speaker::Me => m.message.clone(),
+                    Speaker::Bot => m.message.clone(),
+                })
+                .collect();
+            let msg_start = std::cmp::max(all_messages.len() - 50, 0);
+
+            info!(
+                "Summary: {}",
+                sumi.summary(&all_messages[msg_start..].join("\n")).unwrap()
+            );
         }
+
+        history_file.history
     }
 
-    pub fn add_to_journel(&self, speaker: Speaker, message: &str) {
-        let mut my_history = self.history.lock().unwrap();
+    pub fn add_to_journel(&self, session: &str, speaker: Speaker, message: &str) -> Option<u64> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let my_history = match sessions.get_mut(session) {
+            Some(state) => &mut state.history,
+            None => return None,
+        };
         let new_id;
         if let Some(last_item) = my_history.last() {
             new_id = last_item.id + 1;
@@ -218,14 +399,89 @@ impl Conv {
             speaker,
             id: new_id,
             message: message.to_string(),
-        })
+            rating: None,
+        });
+        Some(new_id)
     }
 
-    pub fn say(&self, input: &str) -> Result<String, Error> {
+    /// Stores a rating against a journal entry
+    ///
+    /// Returns false if there is no such entry
+    pub fn rate(&self, session: &str, id: u64, rating: i8) -> bool {
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions
+            .get_mut(session)
+            .and_then(|state| state.history.iter_mut().find(|past| past.id == id))
+        {
+            Some(past) => {
+                past.rating = Some(rating);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Says something else in place of a reply
+    ///
+    /// Only the last reply can be regenerated since the
+    /// conversation has moved on from any earlier ones
+    pub fn regenerate(&self, session: &str, id: u64) -> Result<String, Error> {
+        let input = {
+            let mut conversation_manager = self.manager.lock().unwrap();
+            let mut sessions = self.sessions.lock().unwrap();
+            let state = sessions
+                .get_mut(session)
+                .ok_or(Error::ConversationUnknown)?;
+            let input = match state.history.as_slice() {
+                [.., Past {
+                    speaker: Speaker::Me,
+                    message,
+                    ..
+                }, Past {
+                    speaker: Speaker::Bot,
+                    id: last_id,
+                    ..
+                }] if *last_id == id => message.clone(),
+                _ => return Err(Error::UnableToRegenerate),
+            };
+            // Go back to before the reply was made
+            let conversation = conversation_manager
+                .get(&state.uuid)
+                .ok_or(Error::ConversationUnknown)?;
+            let earlier = &state.history[..state.history.len() - 2];
+            state.seed_turns = self.load_context(conversation, &state.profile, earlier);
+            input
+        };
+
+        let output = match self.say(session, &input) {
+            Ok(output) => output,
+            Err(e) => {
+                // The journal still has the old reply so the context
+                // goes back to match it
+                if self.reseed(session).is_err() {
+                    error!("Couldn't restore the context of {}", session);
+                }
+                return Err(e);
+            }
+        };
+        if let Some(state) = self.sessions.lock().unwrap().get_mut(session) {
+            if let Some(past) = state.history.iter_mut().find(|past| past.id == id) {
+                past.message = output.clone();
+                past.rating = None;
+            }
+        }
+        Ok(output)
+    }
+
+    pub fn say(&self, session: &str, input: &str) -> Result<String, Error> {
         trace!("  Conv recieved: {}", input);
         let mut conversation_manager = self.manager.lock().unwrap();
-        if let Some(convo) = conversation_manager.get(&self.uuid).as_mut() {
-            self.trim_context(convo);
+        let mut sessions = self.sessions.lock().unwrap();
+        let state = sessions
+            .get_mut(session)
+            .ok_or(Error::ConversationUnknown)?;
+        if let Some(convo) = conversation_manager.get(&state.uuid).as_mut() {
+            self.trim_context(convo, state.seed_turns);
             //let input = Self::swap_persons(input);
             //trace!("  Persons swapped: {}", input);
             if convo.add_user_input(input).is_err() {
@@ -236,51 +492,57 @@ impl Conv {
         }
         let output = {
             trace!("  Generating responses");
-            let resp = self.model.generate_responses(&mut conversation_manager);
+            let resp = self
+                .model
+                .with(|model| model.generate_responses(&mut conversation_manager));
             trace!("  Got responses: {:?}", resp);
-            if let Some(my_resp) = resp.get(&self.uuid) {
+            if let Some(my_resp) = resp.get(&state.uuid) {
                 Ok(my_resp.to_string())
             } else {
                 Err(Error::UnableToSpeak)
             }
         }?;
-        self.past.lock().unwrap().push(input.to_owned());
+        state.past.push(input.to_owned());
         Ok(output)
     }
 
-    fn trim_context(&self, convo: &mut Conversation) {
+    /// Trims the context to `max_context` while keeping
+    /// the profile seed turns at the start
+    fn trim_context(&self, convo: &mut Conversation, seeds: usize) {
         if self.max_context > 0 {
-            if convo.past_user_inputs.len() > self.max_context {
+            let max_turns = self.max_context + seeds;
+            if convo.past_user_inputs.len() > max_turns {
                 trace!("Old UserInput len: {:?}", convo.past_user_inputs.len());
-                let drain_amount = convo.past_user_inputs.len() - self.max_context;
-                convo.past_user_inputs.drain(0..drain_amount);
+                let drain_amount = convo.past_user_inputs.len() - max_turns;
+                convo.past_user_inputs.drain(seeds..seeds + drain_amount);
                 trace!("New UserInput len: {:?}", convo.past_user_inputs.len());
             }
-            if convo.generated_responses.len() > self.max_context {
+            if convo.generated_responses.len() > max_turns {
                 trace!("Old GenResp len: {:?}", convo.generated_responses.len());
-                let drain_amount = convo.generated_responses.len() - self.max_context;
-                convo.generated_responses.drain(0..drain_amount);
+                let drain_amount = convo.generated_responses.len() - max_turns;
+                convo.generated_responses.drain(seeds..seeds + drain_amount);
                 trace!("New GenResp len: {:?}", convo.generated_responses.len());
             }
             let expected_history_size =
                 convo.generated_responses.len() + convo.past_user_inputs.len();
             if convo.history.len() > expected_history_size {
-                trace!("Old Hist len: {:?}", convo.generated_responses.len());
+                trace!("Old Hist len: {:?}", convo.history.len());
                 let drain_amount = convo.history.len() - expected_history_size;
-                convo.history.drain(0..drain_amount);
-                trace!("New Hist len: {:?}", convo.generated_responses.len());
+                convo.history.drain(seeds * 2..seeds * 2 + drain_amount);
+                trace!("New Hist len: {:?}", convo.history.len());
             }
         }
     }
 
-    pub fn save_journal(&self, file_path: &str) -> Result<(), Error> {
-        let my_history = self.history.lock().unwrap();
+    pub fn save_journal(&self, session: &str) -> Result<(), Error> {
+        let history = match self.sessions.lock().unwrap().get(session) {
+            Some(state) => state.history.clone(),
+            None => return Err(Error::ConversationUnknown),
+        };
+        let _saving = self.save_lock.lock();
         if std::fs::write(
-            file_path,
-            toml::to_vec(&History {
-                history: (*my_history).clone(),
-            })
-            .unwrap(),
+            journal_path(session),
+            toml::to_vec(&History { history }).unwrap(),
         )
         .is_err()
         {
@@ -328,48 +590,172 @@ impl Conv {
     }
 }
 
-pub fn start_conv(appctl: &AppCtl, model_name: &str, max_context: usize, do_summary: bool) {
+/// Stores a fact from `/remember key=value` and says how it went
+fn remember(conv: &Conv, session: &str, args: &str) -> String {
+    conv.open_session(session);
+    let reply = match Profile::parse_remember(args) {
+        Some((key, value)) => match conv.remember_fact(session, &key, &value) {
+            Ok(_) => format!("...I will remember your {} is {}...", key, value),
+            Err(_) => "...I couldn't remember that...".to_string(),
+        },
+        None => "...Usage: /remember key=value...".to_string(),
+    };
+    if conv.save_profile(session).is_err() {
+        error!("Failed to write profile.");
+    }
+    reply
+}
+
+pub fn start_conv(
+    appctl: &AppCtl,
+    model_name: &str,
+    max_context: usize,
+    do_summary: bool,
+    learn_profile: bool,
+    idle: Option<Duration>,
+) {
     defer_on_unwind! { appctl.stop() }
-    let mut get_from_me = appctl.listen_me_channel();
+    defer! { appctl.finish(CONVERSATION) }
+    let get_events = appctl.serve(CONVERSATION);
 
-    debug!("Conversation model: Loading");
-    let mut conv_prep = Conv::new(model_name, max_context);
+    // The models are loaded when first needed
+    let mut conv_prep = Conv::new(appctl, model_name, max_context, idle);
     conv_prep.do_summary = do_summary;
+    if learn_profile {
+        conv_prep.enti = Some(Lazy::new(
+            appctl,
+            "entity",
+            model_path("default"),
+            idle,
+            Enti::new,
+        ));
+    }
 
     let conv = Arc::new(conv_prep);
-    if conv.remember_past("./journal.toml").is_err() {
-        error!("They couldn't remember the past.");
-    }
 
     while appctl.is_alive() {
-        match get_from_me.recv_timeout(RX_TIMEOUT) {
-            Ok(input) => {
-                conv.add_to_journel(Speaker::Me, &input);
-
-                match conv.say(&input) {
-                    Err(Error::UnableToHear) => error!("Couldn't hear you"),
-                    Err(Error::UnableToSpeak) => error!("Couldn't speak to you"),
-                    Err(Error::ConversationUnknown) => error!("Doesn't know you"),
-                    Err(_) => {}
+        match get_events.recv_timeout(RX_TIMEOUT) {
+            Ok(Event::Said { origin, text }) => {
+                let session = &origin.session;
+                if let Some((Command::Remember, args)) = Command::parse(&text) {
+                    let reply = remember(&conv, session, args);
+                    appctl.update_session(session, conv.status(session));
+                    appctl.broadcast(Event::Reply {
+                        origin,
+                        text: reply,
+                        id: None,
+                    });
+                    continue;
+                }
+                trace!("  {:?} said in {}", origin.frontend, session);
+                conv.open_session(session);
+                conv.add_to_journel(session, Speaker::Me, &text);
+                match conv.learn(session, &text) {
+                    Ok(true) => {
+                        if conv.save_profile(session).is_err() {
+                            error!("Failed to write profile.");
+                        }
+                    }
+                    Ok(false) => {}
+                    Err(_) => error!("Couldn't learn about you"),
+                }
+
+                let started = Instant::now();
+                match conv.say(session, &text) {
+                    Err(e) => {
+                        match e {
+                            Error::UnableToHear => error!("Couldn't hear you"),
+                            Error::UnableToSpeak => error!("Couldn't speak to you"),
+                            Error::ConversationUnknown => error!("Doesn't know you"),
+                            _ => {}
+                        }
+                        if conv.save_journal(session).is_err() {
+                            error!("Failed to write journal.");
+                        }
+                        // Still answer so the frontend isn't left waiting
+                        appctl.broadcast(Event::Reply {
+                            origin,
+                            text: "...Sorry, I lost my train of thought...".to_string(),
+                            id: None,
+                        });
+                    }
                     Ok(output) => {
-                        conv.add_to_journel(Speaker::Bot, &output);
-                        appctl.broadcast_bot_channel(&output);
+                        appctl.record_generation(started.elapsed());
+                        let id = conv.add_to_journel(session, Speaker::Bot, &output);
+                        // Saved every turn so nothing is lost if the bot is killed
+                        if conv.save_journal(session).is_err() {
+                            error!("Failed to write journal.");
+                        }
+                        appctl.update_session(session, conv.status(session));
+                        appctl.broadcast(Event::Reply {
+                            origin,
+                            text: output,
+                            id,
+                        });
+                    }
+                }
+            }
+            Ok(Event::Rate { origin, id, rating }) => {
+                let session = &origin.session;
+                if conv.rate(session, id, rating) {
+                    if conv.save_journal(session).is_err() {
+                        error!("Failed to write journal.");
                     }
+                } else {
+                    warn!("Nothing to rate in {} with id {}", session, id);
                 }
             }
+            Ok(Event::Regenerate { origin, id }) => {
+                let started = Instant::now();
+                let text = match conv.regenerate(&origin.session, id) {
+                    Ok(output) => {
+                        appctl.record_generation(started.elapsed());
+                        appctl.update_session(&origin.session, conv.status(&origin.session));
+                        if conv.save_journal(&origin.session).is_err() {
+                            error!("Failed to write journal.");
+                        }
+                        Some(output)
+                    }
+                    Err(_) => {
+                        warn!("Couldn't regenerate {} in {}", id, origin.session);
+                        None
+                    }
+                };
+                appctl.broadcast(Event::Regenerated { origin, id, text });
+            }
+            Ok(_) => {}
             Err(RecvTimeoutError::Disconnected) => {
                 appctl.stop();
                 error!("User communication channel dropped.");
                 break;
             }
             Err(RecvTimeoutError::Timeout) => {
+                conv.unload_idle();
                 continue;
             }
         }
     }
     info!("Leaving town");
-    if conv.save_journal("./journal.toml").is_err() {
-        error!("Failed to write journal.");
+    // What is being said is finished above, anything still
+    // waiting is dropped but ratings are quick to keep
+    while let Ok(event) = get_events.try_recv() {
+        match event {
+            Event::Rate { origin, id, rating } => {
+                conv.rate(&origin.session, id, rating);
+            }
+            Event::Said { origin, .. } | Event::Regenerate { origin, .. } => {
+                debug!("Dropping request {} to shut down", origin.request);
+            }
+            _ => {}
+        }
+    }
+    for session in conv.sessions() {
+        if conv.save_journal(&session).is_err() {
+            error!("Failed to write journal.");
+        }
+        if conv.save_profile(&session).is_err() {
+            error!("Failed to write profile.");
+        }
     }
     appctl.stop();
 }
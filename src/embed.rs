@@ -0,0 +1,115 @@
+use rust_bert::pipelines::sentence_embeddings::{
+    Embedding, SentenceEmbeddingsBuilder, SentenceEmbeddingsModel, SentenceEmbeddingsModelType,
+};
+
+use crate::models::Lazy;
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use log::*;
+
+pub struct Embed {
+    model: SentenceEmbeddingsModel,
+}
+
+impl Embed {
+    pub fn new(model_name: &str) -> Self {
+        let sentence_embeddings_model = if model_name == "default" {
+            SentenceEmbeddingsBuilder::remote(SentenceEmbeddingsModelType::AllMiniLmL12V2)
+                .create_model()
+                .expect("Unable to setup model")
+        } else {
+            SentenceEmbeddingsBuilder::local(format!("./{}.model", model_name))
+                .create_model()
+                .expect("Unable to setup model")
+        };
+
+        Self {
+            model: sentence_embeddings_model,
+        }
+    }
+
+    pub fn embed(&self, inputs: &[&str]) -> Option<Vec<Embedding>> {
+        match self.model.encode(inputs) {
+            Ok(embeddings) => Some(embeddings),
+            Err(e) => {
+                error!("Embeddings failed: {}", e);
+                None
+            }
+        }
+    }
+}
+
+/// Embeddings of words saved to disk so they are
+/// not recomputed on every start
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct EmbeddingCache {
+    model_name: String,
+    embeddings: HashMap<String, Embedding>,
+}
+
+impl EmbeddingCache {
+    /// Loads the cache, discarding it if it was made by another model
+    pub fn load(file_path: &str, model_name: &str) -> Self {
+        let cache: Option<Self> = std::fs::read_to_string(file_path)
+            .ok()
+            .and_then(|cache_str| serde_json::from_str(&cache_str).ok());
+        match cache {
+            Some(cache) if cache.model_name == model_name => cache,
+            _ => Self {
+                model_name: model_name.to_string(),
+                embeddings: Default::default(),
+            },
+        }
+    }
+
+    pub fn save(&self, file_path: &str) -> Result<(), String> {
+        serde_json::to_vec(self)
+            .map_err(|e| e.to_string())
+            .and_then(|cache_bytes| {
+                std::fs::write(file_path, cache_bytes).map_err(|e| e.to_string())
+            })
+            .map_err(|e| format!("Embeddings: Error cache not writable: {}", e))
+    }
+
+    pub fn get(&self, word: &str) -> Option<&Embedding> {
+        self.embeddings.get(word)
+    }
+
+    /// Embeds any of the words not already in the cache
+    ///
+    /// Returns true if the cache changed
+    /// The model is only loaded when there is something to embed
+    pub fn extend(&mut self, embed: &Lazy<Embed>, words: &[&str]) -> bool {
+        let missing: Vec<&str> = words
+            .iter()
+            .filter(|word| !self.embeddings.contains_key(**word))
+            .copied()
+            .collect();
+        if missing.is_empty() {
+            return false;
+        }
+        debug!("Embeddings: Embedding {} words", missing.len());
+        match embed.with(|embed| embed.embed(&missing)) {
+            Some(embeddings) => {
+                for (word, embedding) in missing.iter().zip(embeddings.into_iter()) {
+                    self.embeddings.insert(word.to_string(), embedding);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f64 {
+    let dot: f32 = a.iter().zip(b.iter()).map(|(a, b)| a * b).sum();
+    let norm_a: f32 = a.iter().map(|a| a * a).sum::<f32>().sqrt();
+    let norm_b: f32 = b.iter().map(|b| b * b).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        0.0
+    } else {
+        (dot / (norm_a * norm_b)) as f64
+    }
+}
@@ -17,4 +17,11 @@ impl Enti {
     pub fn entities(&self, input: &str) -> Option<Entity> {
         self.model.predict(&[input]).pop().and_then(|mut p| p.pop())
     }
+
+    pub fn all_entities(&self, input: &str) -> Vec<Entity> {
+        self.model
+            .predict_full_entities(&[input])
+            .pop()
+            .unwrap_or_default()
+    }
 }
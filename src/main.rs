@@ -8,13 +8,16 @@
 
 use crossbeam::scope;
 
+use std::collections::HashMap;
 use std::io;
 use std::io::prelude::*;
-use std::sync::mpsc::RecvTimeoutError;
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use clap::Parser;
+use crossbeam_channel::RecvTimeoutError;
 use err_derive::Error;
 use log::*;
 use scopeguard::defer_on_unwind;
@@ -22,22 +25,33 @@ use tokio::runtime::Runtime;
 use validator::Validate;
 
 mod appctl;
+mod backoff;
 mod classy;
+mod command;
 mod config;
 mod conv;
+mod embed;
 mod enti;
+mod models;
+mod profile;
 mod senti;
 mod sumi;
 mod telegram;
+mod webhook;
 mod wordimage;
 
-use self::appctl::AppCtl;
+use self::appctl::{AppCtl, Event, Frontend, CONVERSATION, WORD_IMAGES};
+use self::command::{handle, Handled, BUSY_REPLY, SHUTDOWN_REPLY, TIMEOUT_REPLY};
 use self::config::Config;
-use self::conv::start_conv;
+use self::conv::{start_conv, DEFAULT_SESSION};
 use self::telegram::start_telegram;
 use self::wordimage::start_wordimages;
 
 const RX_TIMEOUT: Duration = Duration::from_millis(500);
+/// How long the console waits for the bot before checking for input again
+const CONSOLE_POLL: Duration = Duration::from_millis(100);
+/// How long frontends wait for a reply and its picture before giving up
+const REPLY_TIMEOUT: Duration = Duration::from_secs(300);
 
 /// Enum of applicable errors
 #[derive(Debug, Error)]
@@ -58,6 +72,12 @@ pub enum Error {
     /// file
     #[error(display = "Can't remember what happened")]
     UnableToWriteJournel,
+    /// Occurs if the profile fails to save
+    #[error(display = "Can't remember who you are")]
+    UnableToWriteProfile,
+    /// Occurs if a reply can't be regenerated
+    #[error(display = "Can't think of another reply")]
+    UnableToRegenerate,
     /// Occurs if the config file fails to validate
     #[error(display = "Config file invalid")]
     ValidationError(#[error(source)] validator::ValidationErrors),
@@ -83,7 +103,7 @@ struct Opts {
     ///
     /// By default the app will use telegram if
     /// the IDs are given. This forces the use of
-    /// the terminal even if the IDs are given
+    /// only the terminal even if the IDs are given
     #[clap(short = 't', long = "terminal")]
     force_terminal: bool,
 }
@@ -103,134 +123,292 @@ fn main() -> Result<(), Error> {
 
     info!("Finding {}", config.bot_name);
 
-    let appctl = Arc::new(AppCtl::new());
+    let appctl = Arc::new(AppCtl::new(config.queue_size, &opts.config));
 
     debug!("Setting up stop signals");
+    let shutdown_timeout = Duration::from_secs(config.shutdown_timeout);
     let appctl_arc = appctl.clone();
+    // Handles SIGTERM too so docker stop is as clean as Ctrl-C
     ctrlc::set_handler(move || {
-        if !appctl_arc.is_alive() {
-            std::process::exit(1);
-        } else {
+        if appctl_arc.is_alive() {
+            info!("Shutting down");
             appctl_arc.stop();
+        } else {
+            warn!("Stopping now");
+            appctl_arc.exit(1);
         }
     })
     .expect("Error setting Ctrl-C handler");
 
+    // Not scoped so it can't hold up a clean exit
+    let appctl_arc = appctl.clone();
+    std::thread::spawn(move || {
+        while appctl_arc.is_alive() {
+            std::thread::sleep(RX_TIMEOUT);
+        }
+        std::thread::sleep(shutdown_timeout);
+        let unfinished: Vec<&str> = [CONVERSATION, WORD_IMAGES]
+            .iter()
+            .copied()
+            .filter(|name| !appctl_arc.is_finished(name))
+            .collect();
+        let waiting_on = if unfinished.is_empty() {
+            "the frontends".to_string()
+        } else {
+            unfinished.join(" and ")
+        };
+        error!(
+            "Still waiting on {} after {:?}, leaving anyway",
+            waiting_on, shutdown_timeout
+        );
+        appctl_arc.exit(1);
+    });
+
     scope(|s| {
         let appctl_arc = appctl.clone();
         let model_name = config.model_name.clone();
         let max_context = config.max_context;
         let do_summary = config.do_summary;
+        let learn_profile = config.learn_profile;
+        let model_idle = config.model_idle();
         s.spawn(move |_| {
-            start_conv(&appctl_arc, &model_name, max_context, do_summary);
+            start_conv(
+                &appctl_arc,
+                &model_name,
+                max_context,
+                do_summary,
+                learn_profile,
+                model_idle,
+            );
         });
 
         let appctl_arc = appctl.clone();
-        let model_name = config.classify_model_name.clone();
+        let classify_model_name = config.classify_model_name.clone();
+        let embed_model_name = config.embed_model_name.clone();
         let word_images = config.word_images.clone();
+        let model_idle = config.model_idle();
         s.spawn(move |_| {
-            start_wordimages(&appctl_arc, &model_name, word_images);
+            start_wordimages(
+                &appctl_arc,
+                &classify_model_name,
+                &embed_model_name,
+                word_images,
+                model_idle,
+            );
         });
 
-        let appctl_arc = appctl.clone();
-        let telegram_token = config.telegram_token.clone();
-        let telegram_id = config.telegram_id;
-        let bot_name = config.bot_name.clone();
-        let force_terminal = opts.force_terminal;
-        s.spawn(move |_| {
-            if force_terminal || telegram_token.is_none() || telegram_id.is_none() {
-                console_input(&appctl_arc, &bot_name);
-            } else if let (Some(token), Some(id)) = (telegram_token, telegram_id) {
+        let telegram = match (config.telegram_token.clone(), config.telegram_id) {
+            (Some(token), Some(id)) if !opts.force_terminal => Some((token, id)),
+            _ => None,
+        };
+        // The console is on by default only when telegram isn't used
+        let console = opts.force_terminal || config.console.unwrap_or(telegram.is_none());
+        if !console && telegram.is_none() {
+            error!("No way to talk, enable the console or set up telegram");
+            appctl.stop();
+        }
+
+        if console {
+            let appctl_arc = appctl.clone();
+            let bot_name = config.bot_name.clone();
+            let console_mirror = config.console_mirror;
+            let only_frontend = telegram.is_none();
+            s.spawn(move |_| {
+                console_input(&appctl_arc, &bot_name, console_mirror, only_frontend);
+            });
+        }
+
+        if let Some((token, id)) = telegram {
+            let appctl_arc = appctl.clone();
+            let telegram_groups = config.telegram_groups.clone();
+            let bot_name = config.bot_name.clone();
+            let telegram_webhook = config.telegram_webhook.clone();
+            let telegram_max_retries = config.telegram_max_retries;
+            let telegram_format = config.telegram_format;
+            s.spawn(move |_| {
                 // Create the runtime
                 let mut rt = Runtime::new().unwrap();
-                let _ = rt.block_on(start_telegram(&appctl_arc, &token, id, &bot_name));
-            }
-        });
+                let _ = rt.block_on(start_telegram(
+                    &appctl_arc,
+                    &token,
+                    id,
+                    &telegram_groups,
+                    &bot_name,
+                    telegram_webhook.as_ref(),
+                    telegram_max_retries,
+                    telegram_format,
+                ));
+            });
+        }
     })
     .unwrap();
 
     Ok(())
 }
 
-fn console_input(appctl: &AppCtl, bot_name: &str) {
-    defer_on_unwind! { appctl.stop(); }
-    let mut get_from_bot = appctl.listen_bot_channel();
-    let mut get_picture_from_bot = appctl.listen_bot_pic_channel();
+fn prompt() {
+    print!("You: ");
+    io::stdout().flush().expect("Could not flush stdout");
+}
 
-    debug!("Starting conv");
-    while appctl.is_alive() {
-        print!("You: ");
-        let mut input = String::new();
-        io::stdout().flush().expect("Could not flush stdout");
+/// Reads lines from stdin on their own thread so the console
+/// can keep up with the bot while waiting for input
+///
+/// The thread is not scoped as it can't be woken from
+/// reading to shut down
+fn read_lines() -> Receiver<String> {
+    let (lines, receiver) = channel();
+    std::thread::spawn(move || {
         let stdin = io::stdin();
-        let mut write_stdin = stdin.lock();
-        'outer: while appctl.is_alive() {
-            let buffer = write_stdin.fill_buf().unwrap();
-            for (i, byte) in buffer.iter().enumerate() {
-                if byte == &0x0A {
-                    input = String::from_utf8_lossy(&buffer[0..i]).into_owned();
-                    write_stdin.consume(i + 1);
-                    break 'outer;
-                }
+        for line in stdin.lock().lines() {
+            match line {
+                Ok(line) if lines.send(line).is_ok() => {}
+                _ => break,
             }
         }
-        if !appctl.is_alive() {
-            break; // Early exit
-        }
-        if input.len() > 1 {
-            let input = match input.chars().last().unwrap() {
-                '!' | '.' | '?' => input.trim().to_string(),
-                _ => format!("{}.", input.trim()),
-            };
-
-            appctl.broadcast_me_channel(&input);
-            while appctl.is_alive() {
-                match get_from_bot.recv_timeout(RX_TIMEOUT) {
-                    Ok(reply) => {
+    });
+    receiver
+}
+
+fn show_image(image_path: &Path) {
+    if let Ok(output) = std::process::Command::new("imgcat")
+        .args([image_path])
+        .output()
+    {
+        println!("{}", String::from_utf8_lossy(&output.stdout).into_owned());
+    } else {
+        error!("Failed to show imgcat for {:?}", image_path);
+    }
+}
+
+/// Talks to the bot from the terminal
+///
+/// Only replies to what was said here are shown unless
+/// mirror is set, then everything said elsewhere is shown too
+///
+/// The end of input only shuts the bot down when the console
+/// is the only way to talk to it
+fn console_input(appctl: &AppCtl, bot_name: &str, mirror: bool, only_frontend: bool) {
+    defer_on_unwind! { appctl.stop(); }
+    let get_events = appctl.listen("console");
+    let lines = read_lines();
+    // Requests still waiting for their picture with when they
+    // were asked and whether the reply has been shown
+    let mut waiting: HashMap<u64, (Instant, bool)> = HashMap::new();
+
+    debug!("Starting conv");
+    prompt();
+    // Replies already being written are still shown after stopping
+    let writing = |waiting: &HashMap<u64, (Instant, bool)>| {
+        !waiting.is_empty() && !(appctl.is_finished(WORD_IMAGES) && get_events.is_empty())
+    };
+    while appctl.is_alive() || writing(&waiting) {
+        match lines.try_recv() {
+            Ok(_) if !appctl.is_alive() => {}
+            Ok(input) => {
+                let input = input.trim();
+                if input.is_empty() {
+                    prompt();
+                    continue;
+                }
+                match handle(appctl, input) {
+                    Handled::Reply(reply) => {
                         println!("{}: {}", bot_name, reply);
-                        break;
+                        prompt();
                     }
-                    Err(RecvTimeoutError::Disconnected) => {
-                        appctl.stop();
-                        error!("Bot communication channel dropped.");
-                        break;
+                    Handled::Cancel(reply) => {
+                        waiting.clear();
+                        println!("{}: {}", bot_name, reply);
+                        prompt();
                     }
-                    Err(RecvTimeoutError::Timeout) => {
-                        continue;
+                    Handled::Shutdown(reply) => {
+                        println!("{}: {}", bot_name, reply);
+                        appctl.stop();
                     }
-                }
-            }
-            while appctl.is_alive() {
-                match get_picture_from_bot.recv_timeout(RX_TIMEOUT) {
-                    Ok(image_path) => {
-                        if let Some(image_path) = image_path {
-                            if let Ok(output) = std::process::Command::new("imgcat")
-                                .args([&image_path])
-                                .output()
-                            {
-                                println!(
-                                    "{}",
-                                    String::from_utf8_lossy(&output.stdout).into_owned()
-                                );
-                            } else {
-                                error!("Failed to show imgcat for {:?}", image_path);
+                    Handled::Say => {
+                        let input = match input.chars().last().unwrap() {
+                            _ if input.starts_with('/') => input.to_string(),
+                            '!' | '.' | '?' => input.to_string(),
+                            _ => format!("{}.", input),
+                        };
+                        let origin = appctl.new_origin(Frontend::Console, DEFAULT_SESSION);
+                        let request = origin.request;
+                        match appctl.request(Event::Said {
+                            origin,
+                            text: input,
+                        }) {
+                            Ok(()) => {
+                                waiting.insert(request, (Instant::now(), false));
+                            }
+                            Err(_) => {
+                                println!("{}: {}", bot_name, BUSY_REPLY);
+                                prompt();
                             }
                         }
-                        break;
-                    }
-                    Err(RecvTimeoutError::Disconnected) => {
-                        appctl.stop();
-                        error!("Bot pic communication channel dropped.");
-                        break;
-                    }
-                    Err(RecvTimeoutError::Timeout) => {
-                        continue;
                     }
                 }
             }
-        } else {
-            appctl.stop();
-            break;
+            Err(TryRecvError::Empty) => {}
+            Err(TryRecvError::Disconnected) if only_frontend => {
+                // End of input
+                appctl.stop();
+            }
+            Err(TryRecvError::Disconnected) => {
+                info!("Console: End of input, still talking on telegram");
+                break;
+            }
         }
+
+        match get_events.recv_timeout(CONSOLE_POLL) {
+            Ok(Event::Reply { origin, text, .. }) if waiting.contains_key(&origin.request) => {
+                println!("{}: {}", bot_name, text);
+                if let Some((_, replied)) = waiting.get_mut(&origin.request) {
+                    *replied = true;
+                }
+            }
+            Ok(Event::Picture { origin, path }) if waiting.remove(&origin.request).is_some() => {
+                if let Some(image_path) = path {
+                    show_image(&image_path);
+                }
+                if waiting.is_empty() {
+                    prompt();
+                }
+            }
+            Ok(Event::Said { origin, text }) if mirror && origin.frontend != Frontend::Console => {
+                println!("[{:?} {}] You: {}", origin.frontend, origin.session, text);
+            }
+            Ok(Event::Reply { origin, text, .. })
+                if mirror && origin.frontend != Frontend::Console =>
+            {
+                println!(
+                    "[{:?} {}] {}: {}",
+                    origin.frontend, origin.session, bot_name, text
+                );
+            }
+            Ok(_) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                appctl.stop();
+                error!("Bot communication channel dropped.");
+                break;
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+        }
+
+        let expired: Vec<u64> = waiting
+            .iter()
+            .filter(|(_, (asked, _))| asked.elapsed() > REPLY_TIMEOUT)
+            .map(|(request, _)| *request)
+            .collect();
+        for request in expired {
+            if let Some((_, false)) = waiting.remove(&request) {
+                println!("{}: {}", bot_name, TIMEOUT_REPLY);
+            }
+            if waiting.is_empty() {
+                prompt();
+            }
+        }
+    }
+    if !waiting.is_empty() {
+        println!("{}: {}", bot_name, SHUTDOWN_REPLY);
     }
 }
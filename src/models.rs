@@ -0,0 +1,92 @@
+use crate::appctl::AppCtl;
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::*;
+
+/// What is known about a model for `/status`
+#[derive(Debug, Clone)]
+pub struct ModelInfo {
+    pub name: &'static str,
+    /// Where it is loaded from
+    pub path: String,
+    pub loaded: bool,
+    pub last_used: Option<Instant>,
+}
+
+/// Where a model named in the config is loaded from
+pub fn model_path(model_name: &str) -> String {
+    if model_name == "default" {
+        "default (downloaded)".to_string()
+    } else {
+        format!("./{}.model", model_name)
+    }
+}
+
+/// A model that is loaded when it is first used and
+/// dropped again once it has sat idle for too long
+pub struct Lazy<T> {
+    info: Arc<Mutex<ModelInfo>>,
+    /// None keeps it loaded once it is
+    idle: Option<Duration>,
+    load: Box<dyn Fn() -> T + Send + Sync>,
+    model: Mutex<Option<T>>,
+}
+
+impl<T> Lazy<T> {
+    pub fn new<F>(
+        appctl: &AppCtl,
+        name: &'static str,
+        path: String,
+        idle: Option<Duration>,
+        load: F,
+    ) -> Self
+    where
+        F: Fn() -> T + Send + Sync + 'static,
+    {
+        Self {
+            info: appctl.track_model(ModelInfo {
+                name,
+                path,
+                loaded: false,
+                last_used: None,
+            }),
+            idle,
+            load: Box::new(load),
+            model: Mutex::new(None),
+        }
+    }
+
+    /// Runs with the model, loading it first if needed
+    pub fn with<R>(&self, use_model: impl FnOnce(&T) -> R) -> R {
+        let mut model = self.model.lock().unwrap();
+        let model = model.get_or_insert_with(|| {
+            let info = self.info.lock().unwrap().clone();
+            info!("Loading {} model from {}", info.name, info.path);
+            let loaded = (self.load)();
+            self.info.lock().unwrap().loaded = true;
+            debug!("{} model: Ready", info.name);
+            loaded
+        });
+        self.info.lock().unwrap().last_used = Some(Instant::now());
+        use_model(model)
+    }
+
+    /// Drops the model if it hasn't been used for the idle time
+    pub fn unload_idle(&self) {
+        let idle = match self.idle {
+            Some(idle) => idle,
+            None => return,
+        };
+        let mut model = self.model.lock().unwrap();
+        let mut info = self.info.lock().unwrap();
+        if let (Some(_), Some(last_used)) = (model.as_ref(), info.last_used) {
+            if last_used.elapsed() >= idle {
+                *model = None;
+                info.loaded = false;
+                info!("Unloaded {} model after {:?} idle", info.name, idle);
+            }
+        }
+    }
+}
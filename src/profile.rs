@@ -0,0 +1,132 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+
+use log::*;
+
+use crate::enti::Enti;
+use crate::Error;
+
+/// Entities scored below this are not trusted enough to remember
+const MIN_ENTITY_SCORE: f64 = 0.9;
+
+/// Phrases that must come right before an entity for it to be
+/// remembered, grouped by the entity label and the fact key
+/// that it is stored under
+const ENTITY_CUES: &[(&str, &str, &[&str])] = &[
+    ("PER", "name", &["my name is", "call me", "i am", "i'm"]),
+    (
+        "LOC",
+        "home",
+        &["i live in", "i am from", "i'm from", "my home is"],
+    ),
+];
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct Profile {
+    /// Keys set with `/remember` that learning leaves alone
+    #[serde(default)]
+    by_hand: BTreeSet<String>,
+    #[serde(default)]
+    facts: BTreeMap<String, String>,
+}
+
+impl Profile {
+    pub fn load(file_path: &str) -> Self {
+        match fs::read_to_string(file_path) {
+            Ok(profile_str) => toml::from_str(&profile_str).unwrap_or_else(|e| {
+                error!("Profile is unreadable: {}", e);
+                Default::default()
+            }),
+            Err(_) => {
+                info!("They know nothing about you yet");
+                Default::default()
+            }
+        }
+    }
+
+    pub fn save(&self, file_path: &str) -> Result<(), Error> {
+        if std::fs::write(file_path, toml::to_vec(self).unwrap()).is_err() {
+            Err(Error::UnableToWriteProfile)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Store a fact given by hand, returns true if the profile changed
+    pub fn remember(&mut self, key: &str, value: &str) -> bool {
+        let changed = self.store(key, value);
+        let key = key.trim().to_lowercase();
+        if self.facts.contains_key(&key) {
+            changed | self.by_hand.insert(key)
+        } else {
+            changed
+        }
+    }
+
+    fn store(&mut self, key: &str, value: &str) -> bool {
+        let key = key.trim().to_lowercase();
+        let value = value.trim().to_string();
+        if key.is_empty() || value.is_empty() {
+            return false;
+        }
+        if self.facts.get(&key) == Some(&value) {
+            false
+        } else {
+            debug!("Remembering {} = {}", key, value);
+            self.facts.insert(key, value);
+            true
+        }
+    }
+
+    /// Parses the argument of `/remember key=value`
+    pub fn parse_remember(args: &str) -> Option<(String, String)> {
+        let mut parts = args.splitn(2, '=');
+        let key = parts.next()?.trim();
+        let value = parts.next()?.trim();
+        if key.is_empty() || value.is_empty() {
+            None
+        } else {
+            Some((key.to_string(), value.to_string()))
+        }
+    }
+
+    /// Looks for facts in what was said using named entities
+    ///
+    /// Returns true if the profile changed
+    pub fn learn(&mut self, enti: &Enti, input: &str) -> bool {
+        let lower_input = input.to_lowercase();
+        let mut changed = false;
+        for entity in enti.all_entities(input) {
+            if entity.score < MIN_ENTITY_SCORE {
+                continue;
+            }
+            let word = entity.word.to_lowercase();
+            for (label, key, cues) in ENTITY_CUES {
+                if entity.label.ends_with(label)
+                    && !self.by_hand.contains(*key)
+                    && cues
+                        .iter()
+                        .any(|cue| lower_input.contains(&format!("{} {}", cue, word)))
+                {
+                    changed |= self.store(key, &entity.word);
+                }
+            }
+        }
+        changed
+    }
+
+    /// Renders each fact as a pair of user input and bot reply
+    pub fn seed_turns(&self) -> Vec<(String, String)> {
+        self.facts
+            .iter()
+            .map(|(key, value)| {
+                let key = key.replace('_', " ");
+                (
+                    format!("My {} is {}.", key, value),
+                    format!("I will remember that your {} is {}.", key, value),
+                )
+            })
+            .collect()
+    }
+}
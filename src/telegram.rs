@@ -1,181 +1,1397 @@
-use crate::appctl::AppCtl;
+use crate::appctl::{AppCtl, Event, Frontend, Origin, WORD_IMAGES};
+use crate::backoff::Backoff;
+use crate::command::{handle, Handled, BUSY_REPLY, COMMANDS, SHUTDOWN_REPLY, TIMEOUT_REPLY};
+use crate::config::{TelegramFormat, WebhookConfig};
+use crate::conv::DEFAULT_SESSION;
+use crate::webhook::{start_webhook, SetWebhook};
+use crate::REPLY_TIMEOUT;
 
+use futures::future::{AbortHandle, Abortable};
 use futures::StreamExt;
-use futures::future::{Abortable, AbortHandle};
 
-use std::sync::mpsc::RecvTimeoutError;
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use crossbeam_channel::Receiver;
 use scopeguard::defer_on_unwind;
-use telegram_bot::{Api, UpdateKind, UserId, Integer, MessageChat, MessageKind, InputFileUpload, CanReplySendMessage, CanReplySendPhoto, Error as TeleError, reply_markup, ReplyKeyboardMarkup};
+use serde::Serialize;
+use telegram_bot::{
+    reply_markup, Api, CallbackQuery, CanAnswerCallbackQuery, CanDeleteMessage, CanEditMessageText,
+    CanReplySendMessage, CanReplySendPhoto, CanSendChatAction, ChatAction, ChatRef,
+    Error as TeleError, GetMe, InlineKeyboardButton, InlineKeyboardMarkup, InputFileUpload,
+    Integer, Message, MessageChat, MessageKind, MessageOrChannelPost, ParseMode,
+    ReplyKeyboardMarkup, Update, UpdateKind, UpdatesStream, UserId,
+};
+use telegram_bot_raw::{
+    Error as RawError, HttpRequest, JsonIdResponse, JsonRequestType, JsonTrueToUnitResponse,
+    MessageId, ReplyMarkup, Request as TeleRequest, RequestType, RequestUrl, ToChatRef,
+    ToMessageId, ToSourceChat,
+};
+use tokio::sync::mpsc::UnboundedReceiver;
 
 use log::*;
 
-use crate::RX_TIMEOUT;
+/// How often the bot channels are checked for replies
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
 
-enum TeleCommand {
-    Images(bool),
-    Shutdown,
-    None,
+/// First delay before retrying telegram
+const RETRY_BASE: Duration = Duration::from_secs(1);
+/// Longest delay between retries
+const RETRY_MAX: Duration = Duration::from_secs(60);
+
+/// Longest caption telegram allows on a photo
+const CAPTION_LIMIT: usize = 1024;
+/// Longest text telegram allows in a message
+const MESSAGE_LIMIT: usize = 4096;
+/// Characters that start and end formatting in a reply
+const FORMAT_MARKERS: &[char] = &['*', '_', '`'];
+
+/// Telegram shows a chat action for five seconds so it is resent before then
+const CHAT_ACTION_INTERVAL: Duration = Duration::from_secs(4);
+
+/// Said for a photo without a caption
+const PHOTO_TEXT: &str = "Look at this photo.";
+/// Said for a file without a caption
+const DOCUMENT_TEXT: &str = "Look at this file.";
+/// Said for a sticker with an emoji that is not known
+const STICKER_TEXT: &str = "Look at this sticker.";
+
+/// Sticker emojis and what they are taken to say
+const STICKER_EMOJIS: &[(&[&str], &str)] = &[
+    (&["😀", "😃", "😄", "😁", "😆", "😸"], "Haha!"),
+    (&["😂", "🤣", "😹"], "That is hilarious!"),
+    (&["🙂", "😊", "☺", "😌"], "That makes me happy."),
+    (&["😍", "🥰", "😘", "❤", "💕", "💖", "😻"], "I love you."),
+    (&["😉", "😏"], "You know what I mean."),
+    (&["😢", "😭", "😿", "💔"], "That makes me sad."),
+    (&["😠", "😡", "🤬", "😾"], "I am angry."),
+    (&["😮", "😲", "😱", "🙀"], "Wow!"),
+    (&["🤔"], "Hmm, let me think."),
+    (&["😴", "🥱"], "I am sleepy."),
+    (&["👋"], "Hello!"),
+    (&["👍", "👌"], "Okay."),
+    (&["👎"], "I do not like that."),
+    (&["🙏"], "Thank you."),
+];
+
+/// Turns a sticker emoji into something the bot can reply to
+fn sticker_text(emoji: &str) -> String {
+    let emoji = emoji.trim_end_matches('\u{fe0f}');
+    STICKER_EMOJIS
+        .iter()
+        .find(|(emojis, _)| emojis.contains(&emoji))
+        .map(|(_, text)| text.to_string())
+        .unwrap_or_else(|| STICKER_TEXT.to_string())
+}
+
+/// The text to send to the conversation for a message
+fn message_text(kind: &MessageKind) -> Option<String> {
+    match kind {
+        MessageKind::Text { data, .. } => Some(data.clone()),
+        MessageKind::Photo { caption, .. } => {
+            Some(caption.clone().unwrap_or_else(|| PHOTO_TEXT.to_string()))
+        }
+        MessageKind::Document { caption, .. } => {
+            Some(caption.clone().unwrap_or_else(|| DOCUMENT_TEXT.to_string()))
+        }
+        MessageKind::Sticker { data } => Some(
+            data.emoji
+                .as_deref()
+                .map(sticker_text)
+                .unwrap_or_else(|| STICKER_TEXT.to_string()),
+        ),
+        _ => None,
+    }
+}
+
+/// Sends the chat action if it has not been sent within the interval
+fn keep_chat_action(
+    api: &Api,
+    chat: &MessageChat,
+    action: ChatAction,
+    last_sent: &mut Option<Instant>,
+) {
+    if last_sent
+        .map(|sent| sent.elapsed() >= CHAT_ACTION_INTERVAL)
+        .unwrap_or(true)
+    {
+        api.spawn(chat.chat_action(action));
+        *last_sent = Some(Instant::now());
+    }
+}
+
+fn get_reply_keyboard(status: &AppCtl) -> ReplyKeyboardMarkup {
+    if status.images_enabled() {
+        reply_markup!(reply_keyboard, selective, ["/stop", "/help"], ["/noimages"])
+    } else {
+        reply_markup!(
+            reply_keyboard,
+            selective,
+            ["/stop", "/help"],
+            ["/yesimages"]
+        )
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+struct BotCommand {
+    command: String,
+    description: String,
+}
+
+/// Fills telegram's command menu
+#[derive(Serialize, Debug, Clone)]
+struct SetMyCommands {
+    commands: Vec<BotCommand>,
+}
+
+impl SetMyCommands {
+    fn from_registry() -> Self {
+        Self {
+            commands: COMMANDS
+                .iter()
+                .map(|(_, name, description)| BotCommand {
+                    command: name.to_string(),
+                    description: description.to_string(),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl TeleRequest for SetMyCommands {
+    type Type = JsonRequestType<Self>;
+    type Response = JsonTrueToUnitResponse;
+
+    fn serialize(&self) -> Result<HttpRequest, RawError> {
+        <Self::Type as RequestType>::serialize(RequestUrl::method("setMyCommands"), self)
+    }
+}
+
+/// Edits the caption under a photo
+///
+/// The one in telegram-bot can't set the parse mode
+#[derive(Serialize, Debug, Clone)]
+struct EditCaption {
+    chat_id: ChatRef,
+    message_id: MessageId,
+    caption: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parse_mode: Option<ParseMode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reply_markup: Option<ReplyMarkup>,
+}
+
+impl EditCaption {
+    fn new(message: &MessageOrChannelPost, caption: &str, format: TelegramFormat) -> Self {
+        Self {
+            chat_id: message.to_source_chat().to_chat_ref(),
+            message_id: message.to_message_id(),
+            caption: render(caption, format),
+            parse_mode: parse_mode(format),
+            reply_markup: None,
+        }
+    }
+}
+
+impl TeleRequest for EditCaption {
+    type Type = JsonRequestType<Self>;
+    type Response = JsonIdResponse<MessageOrChannelPost>;
+
+    fn serialize(&self) -> Result<HttpRequest, RawError> {
+        <Self::Type as RequestType>::serialize(RequestUrl::method("editMessageCaption"), self)
+    }
+}
+
+/// Buttons under a reply to rate it or ask for another
+fn feedback_buttons(session: &str, id: u64) -> InlineKeyboardMarkup {
+    let mut buttons = InlineKeyboardMarkup::new();
+    buttons.add_row(vec![
+        InlineKeyboardButton::callback("👍", format!("up {} {}", session, id)),
+        InlineKeyboardButton::callback("👎", format!("down {} {}", session, id)),
+        InlineKeyboardButton::callback("🔄", format!("again {} {}", session, id)),
+    ]);
+    buttons
+}
+
+/// A feedback button under a reply
+enum Button {
+    Rate(i8),
+    Again,
+}
+
+/// Reads the data sent back when a feedback button is pressed
+fn parse_feedback(data: &str) -> Option<(Button, String, u64)> {
+    let mut words = data.split_whitespace();
+    let button = match words.next()? {
+        "up" => Button::Rate(1),
+        "down" => Button::Rate(-1),
+        "again" => Button::Again,
+        _ => return None,
+    };
+    let session = words.next()?.to_string();
+    let id = words.next()?.parse().ok()?;
+    Some((button, session, id))
+}
+
+fn group_session(chat_id: Integer) -> String {
+    format!("group{}", chat_id)
+}
+
+/// Who the bot is on telegram and who it talks to
+struct Identity {
+    owner: UserId,
+    groups: Vec<i64>,
+    bot_name: String,
+    bot_id: Option<UserId>,
+    username: Option<String>,
+}
+
+impl Identity {
+    /// Asks telegram for the bot's user so mentions and
+    /// replies to it can be noticed in groups
+    async fn fetch(&mut self, api: &Api) {
+        if self.bot_id.is_some() {
+            return;
+        }
+        match api.send(GetMe).await {
+            Ok(user) => {
+                debug!("Telegram: Bot is @{:?}", user.username);
+                self.bot_id = Some(user.id);
+                self.username = user.username;
+            }
+            Err(e) => warn!("Telegram: Unable to get bot user: {:?}", e),
+        }
+    }
+
+    /// Finds what was said to the bot in a group
+    ///
+    /// Returns None unless the bot was mentioned, replied to or
+    /// addressed by name, otherwise the text without the mention
+    fn group_text(&self, message: &Message, text: &str) -> Option<String> {
+        let replied_to = match (&message.reply_to_message, self.bot_id) {
+            (Some(reply_to), Some(bot_id)) => match reply_to.as_ref() {
+                MessageOrChannelPost::Message(replied) => replied.from.id == bot_id,
+                MessageOrChannelPost::ChannelPost(_) => false,
+            },
+            _ => false,
+        };
+
+        let mut text = text.to_string();
+        let mut mentioned = false;
+        if let Some(username) = &self.username {
+            let mention = format!("@{}", username.to_ascii_lowercase());
+            if let Some(start) = text.to_ascii_lowercase().find(&mention) {
+                text.replace_range(start..start + mention.len(), "");
+                mentioned = true;
+            }
+        }
+
+        // Such as "Bot, how are you?"
+        let mut named = false;
+        let name = self.bot_name.to_ascii_lowercase();
+        let trimmed = text.trim_start();
+        if !name.is_empty() && trimmed.to_ascii_lowercase().starts_with(&name) {
+            let rest = &trimmed[name.len()..];
+            if rest.is_empty()
+                || rest.starts_with(|c: char| c.is_whitespace() || c.is_ascii_punctuation())
+            {
+                text = rest
+                    .trim_start_matches(|c: char| c == ',' || c == ':')
+                    .to_string();
+                named = true;
+            }
+        }
+
+        let text = text.trim();
+        if (replied_to || mentioned || named) && !text.is_empty() {
+            Some(text.to_string())
+        } else {
+            None
+        }
+    }
+
+    /// Works out which conversation a message belongs to and what was
+    /// said in it, or None if the bot should ignore it
+    fn conversation(&self, message: &Message, text: &str) -> Option<(String, String)> {
+        let from_owner = message.from.id == self.owner;
+        match &message.chat {
+            MessageChat::Private(_) => {
+                if from_owner && message.reply_to_message.is_none() {
+                    Some((DEFAULT_SESSION.to_string(), text.to_string()))
+                } else {
+                    None
+                }
+            }
+            MessageChat::Group(_) | MessageChat::Supergroup(_) => {
+                let chat_id = Integer::from(message.chat.id());
+                if !self.groups.contains(&chat_id) {
+                    return None;
+                }
+                let text = self.group_text(message, text)?;
+                // Only the owner can control the bot from a group
+                if text.starts_with('/') && !from_owner {
+                    return None;
+                }
+                Some((group_session(chat_id), text))
+            }
+            _ => None,
+        }
+    }
+
+    /// True if the user can give feedback on replies in the session
+    fn takes_feedback(&self, user: UserId, session: &str) -> bool {
+        if session == DEFAULT_SESSION {
+            user == self.owner
+        } else {
+            self.groups
+                .iter()
+                .any(|group| group_session(*group) == session)
+        }
+    }
+}
+
+/// Where updates from telegram come from
+enum Updates<'a> {
+    /// Long polling
+    Poll(UpdatesStream),
+    /// Posted to the webhook listener
+    Webhook(&'a mut UnboundedReceiver<Update>),
+}
+
+impl<'a> Updates<'a> {
+    async fn next(&mut self) -> Option<Result<Update, TeleError>> {
+        match self {
+            Updates::Poll(stream) => stream.next().await,
+            Updates::Webhook(receiver) => receiver.recv().await.map(Ok),
+        }
+    }
+}
+
+/// A message sent to the bot that is waiting for its reply and picture
+struct Pending {
+    message: Message,
+    origin: Origin,
+    reply: Option<String>,
+    id: Option<u64>,
+    picture: Option<Option<PathBuf>>,
+    cancelled: bool,
+    action_sent: Option<Instant>,
+    asked: Instant,
+}
+
+/// One message sent to telegram as part of a reply
+#[derive(Clone)]
+enum Part {
+    Photo {
+        pic: String,
+        caption: Option<String>,
+    },
+    Text(String),
+}
+
+/// Length as telegram counts it
+fn telegram_len(text: &str) -> usize {
+    text.encode_utf16().count()
+}
+
+/// Splits text after each sentence keeping the trailing whitespace
+fn sentences(text: &str) -> Vec<&str> {
+    let mut sentences = vec![];
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let boundary = match chars.peek() {
+            Some(&(_, next)) => c == '\n' || matches!(c, '.' | '!' | '?') && next.is_whitespace(),
+            None => false,
+        };
+        if boundary {
+            let mut end = i + c.len_utf8();
+            while let Some(&(j, next)) = chars.peek() {
+                if !next.is_whitespace() {
+                    break;
+                }
+                end = j + next.len_utf8();
+                chars.next();
+            }
+            sentences.push(&text[start..end]);
+            start = end;
+        }
+    }
+    if start < text.len() {
+        sentences.push(&text[start..]);
+    }
+    sentences
+}
+
+/// Splits a sentence that is too long between words, or
+/// anywhere if a single word is too long
+fn split_long(text: &str, limit: usize) -> Vec<String> {
+    if telegram_len(text) <= limit {
+        return vec![text.to_string()];
+    }
+    let mut pieces = vec![];
+    let mut piece = String::new();
+    for word in text.split_inclusive(char::is_whitespace) {
+        if telegram_len(&piece) + telegram_len(word) > limit && !piece.is_empty() {
+            pieces.push(std::mem::take(&mut piece));
+        }
+        for c in word.chars() {
+            if telegram_len(&piece) + c.len_utf16() > limit {
+                pieces.push(std::mem::take(&mut piece));
+            }
+            piece.push(c);
+        }
+    }
+    if !piece.is_empty() {
+        pieces.push(piece);
+    }
+    pieces
+}
+
+/// Splits a reply between sentences so each part fits in a message
+///
+/// The first part is at most `first_limit` long and is empty if the
+/// first sentence doesn't fit, the rest are at most `limit` long
+fn split_reply(text: &str, first_limit: usize, limit: usize) -> Vec<String> {
+    let mut parts = vec![];
+    let mut part = String::new();
+    for piece in sentences(text)
+        .into_iter()
+        .flat_map(|sentence| split_long(sentence, limit))
+    {
+        let part_limit = if parts.is_empty() { first_limit } else { limit };
+        if telegram_len(&part) + telegram_len(&piece) > part_limit {
+            parts.push(part.trim().to_string());
+            part.clear();
+        }
+        part.push_str(&piece);
+    }
+    if !part.trim().is_empty() {
+        parts.push(part.trim().to_string());
+    }
+    parts
+}
+
+/// Breaks a reply into the messages telegram will accept
+///
+/// A picture gets the start of the reply as its caption and the
+/// rest follows as text
+fn reply_parts(reply: Option<String>, pic: Option<String>) -> Vec<Part> {
+    let reply = reply.unwrap_or_default();
+    match pic {
+        Some(pic) => {
+            let mut texts = split_reply(&reply, CAPTION_LIMIT, MESSAGE_LIMIT).into_iter();
+            let caption = texts.next().filter(|caption| !caption.is_empty());
+            std::iter::once(Part::Photo { pic, caption })
+                .chain(texts.map(Part::Text))
+                .collect()
+        }
+        None => split_reply(&reply, MESSAGE_LIMIT, MESSAGE_LIMIT)
+            .into_iter()
+            .filter(|text| !text.is_empty())
+            .map(Part::Text)
+            .collect(),
+    }
+}
+
+fn parse_mode(format: TelegramFormat) -> Option<ParseMode> {
+    match format {
+        TelegramFormat::Plain => None,
+        TelegramFormat::Html => Some(ParseMode::Html),
+        TelegramFormat::Markdown => Some(ParseMode::Markdown),
+    }
+}
+
+fn escape(text: &str, format: TelegramFormat) -> String {
+    match format {
+        TelegramFormat::Plain => text.to_string(),
+        TelegramFormat::Html => text
+            .replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;"),
+        TelegramFormat::Markdown => {
+            let mut escaped = String::with_capacity(text.len());
+            for c in text.chars() {
+                if matches!(c, '_' | '*' | '`' | '[') {
+                    escaped.push('\\');
+                }
+                escaped.push(c);
+            }
+            escaped
+        }
+    }
+}
+
+/// Renders `*bold*`, `_italic_` and `` `code` `` in a reply
+///
+/// Everything else telegram would read as formatting is escaped
+/// so a stray character can't stop the message being sent
+fn render(text: &str, format: TelegramFormat) -> String {
+    if format == TelegramFormat::Plain {
+        return text.to_string();
+    }
+    let mut rendered = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find(FORMAT_MARKERS) {
+        rendered.push_str(&escape(&rest[..start], format));
+        let marker = &rest[start..start + 1];
+        let after = &rest[start + 1..];
+        // Only text without other formatting or line breaks is rendered
+        let end = after.find(|c: char| FORMAT_MARKERS.contains(&c) || c == '[' || c == '\n');
+        match end {
+            Some(end)
+                if after[end..].starts_with(marker)
+                    && !after[..end].is_empty()
+                    && after[..end].trim().len() == end =>
+            {
+                let inner = &after[..end];
+                match format {
+                    TelegramFormat::Html => {
+                        let tag = match marker {
+                            "*" => "b",
+                            "_" => "i",
+                            _ => "code",
+                        };
+                        rendered.push_str(&format!("<{0}>{1}</{0}>", tag, escape(inner, format)));
+                    }
+                    _ => rendered.push_str(&format!("{0}{1}{0}", marker, inner)),
+                }
+                rest = &after[end + 1..];
+            }
+            _ => {
+                rendered.push_str(&escape(marker, format));
+                rest = after;
+            }
+        }
+    }
+    rendered.push_str(&escape(rest, format));
+    rendered
+}
+
+/// Telegram descriptions of errors that sending again won't fix
+const PERMANENT_ERRORS: &[&str] = &[
+    "bad request",
+    "unauthorized",
+    "forbidden",
+    "not found",
+    "request entity too large",
+    "no such file",
+];
+
+/// What to do about a reply that failed to send
+enum Failure {
+    /// Network trouble or telegram being busy, with how long
+    /// telegram asked to wait if it did
+    Retry(Option<Duration>),
+    /// It would fail the same way again
+    Permanent,
+}
+
+/// Sorts send errors into those worth retrying
+///
+/// telegram-bot doesn't expose the kind of error so this
+/// goes by the description telegram sent
+fn classify(error: &TeleError) -> Failure {
+    let text = format!("{:?}", error).to_lowercase();
+    if text.contains("too many requests") {
+        let retry_after = text
+            .split("retry after")
+            .nth(1)
+            .and_then(|rest| {
+                rest.trim_start()
+                    .split(|c: char| !c.is_ascii_digit())
+                    .next()
+                    .and_then(|seconds| seconds.parse().ok())
+            })
+            .map(Duration::from_secs);
+        Failure::Retry(retry_after)
+    } else if PERMANENT_ERRORS
+        .iter()
+        .any(|permanent| text.contains(permanent))
+    {
+        Failure::Permanent
+    } else {
+        Failure::Retry(None)
+    }
+}
+
+/// A part of a reply that could not be sent yet
+struct Unsent {
+    message: Message,
+    part: Part,
+    buttons: Option<InlineKeyboardMarkup>,
+    /// The session and journal id of the reply it is part of
+    /// if it can be rated or regenerated
+    feedback: Option<(String, u64)>,
+    backoff: Backoff,
+}
+
+/// The messages a reply with feedback buttons was sent in,
+/// kept so regenerating it can replace all of them
+struct SentReply {
+    id: u64,
+    /// The message it replied to
+    message: Message,
+    pic: Option<String>,
+    parts: Vec<MessageOrChannelPost>,
+}
+
+/// Replies waiting to be sent again after a failure
+///
+/// Replies are kept in order so a new reply waits behind
+/// any that failed before it
+struct Outbox {
+    unsent: VecDeque<Unsent>,
+    /// The last reply with feedback buttons in each session
+    replies: HashMap<String, SentReply>,
+    max_retries: u32,
+    format: TelegramFormat,
+}
+
+impl Unsent {
+    /// Records a failure and returns how long to wait
+    fn fail(&mut self, retry_after: Option<Duration>) -> Duration {
+        match retry_after {
+            Some(retry_after) => self.backoff.fail_for(retry_after),
+            None => self.backoff.fail(),
+        }
+    }
+}
+
+impl Outbox {
+    fn new(max_retries: u32, format: TelegramFormat) -> Self {
+        Self {
+            unsent: Default::default(),
+            replies: Default::default(),
+            max_retries,
+            format,
+        }
+    }
+
+    /// Sends each part of the reply now or queues it if it fails
+    /// or earlier parts are still waiting
+    ///
+    /// Feedback buttons for the session and journal id go under
+    /// the last part
+    async fn send(
+        &mut self,
+        api: &Api,
+        appctl: &AppCtl,
+        message: &Message,
+        reply: Option<String>,
+        pic: Option<String>,
+        feedback: Option<(&str, u64)>,
+    ) {
+        self.send_parts(api, appctl, message, reply_parts(reply, pic), feedback)
+            .await;
+    }
+
+    /// Sends a reply that is already split into parts
+    async fn send_parts(
+        &mut self,
+        api: &Api,
+        appctl: &AppCtl,
+        message: &Message,
+        parts: Vec<Part>,
+        feedback: Option<(&str, u64)>,
+    ) {
+        let pic = parts.iter().find_map(|part| match part {
+            Part::Photo { pic, .. } => Some(pic.clone()),
+            Part::Text(_) => None,
+        });
+        let feedback = feedback.map(|(session, id)| (session.to_string(), id));
+        let buttons = feedback
+            .as_ref()
+            .map(|(session, id)| feedback_buttons(session, *id));
+        if let Some((session, id)) = &feedback {
+            self.replies.insert(
+                session.clone(),
+                SentReply {
+                    id: *id,
+                    message: message.clone(),
+                    pic,
+                    parts: Vec::new(),
+                },
+            );
+        }
+        let last = parts.len().saturating_sub(1);
+        for (i, part) in parts.into_iter().enumerate() {
+            let mut unsent = Unsent {
+                message: message.clone(),
+                part,
+                buttons: if i == last { buttons.clone() } else { None },
+                feedback: feedback.clone(),
+                backoff: Backoff::new(RETRY_BASE, RETRY_MAX),
+            };
+            if self.unsent.is_empty() {
+                match send_part(api, appctl, &unsent, self.format).await {
+                    Ok(sent) => {
+                        record_sent(&mut self.replies, &unsent, sent);
+                        continue;
+                    }
+                    Err(e) => match classify(&e) {
+                        Failure::Permanent => {
+                            error!("Telegram: Unable to send reply, dropping it: {:?}", e);
+                            continue;
+                        }
+                        Failure::Retry(retry_after) => {
+                            let delay = unsent.fail(retry_after);
+                            warn!(
+                                "Telegram: Unable to send reply, retrying in {:?}: {:?}",
+                                delay, e
+                            );
+                        }
+                    },
+                }
+            }
+            self.unsent.push_back(unsent);
+        }
+    }
+
+    /// Sends the queued replies whose backoff has passed
+    async fn retry(&mut self, api: &Api, appctl: &AppCtl) {
+        let format = self.format;
+        while let Some(unsent) = self.unsent.front_mut() {
+            if !unsent.backoff.ready() {
+                break;
+            }
+            match send_part(api, appctl, unsent, format).await {
+                Ok(sent) => {
+                    record_sent(&mut self.replies, unsent, sent);
+                    self.unsent.pop_front();
+                }
+                Err(e) => match classify(&e) {
+                    Failure::Permanent => {
+                        error!("Telegram: Unable to send reply, dropping it: {:?}", e);
+                        self.unsent.pop_front();
+                    }
+                    Failure::Retry(_) if unsent.backoff.attempts() >= self.max_retries => {
+                        error!(
+                            "Telegram: Giving up on reply after {} attempts: {:?}",
+                            unsent.backoff.attempts() + 1,
+                            e
+                        );
+                        self.unsent.pop_front();
+                    }
+                    Failure::Retry(retry_after) => {
+                        let delay = unsent.fail(retry_after);
+                        warn!(
+                            "Telegram: Unable to send reply, retrying in {:?}: {:?}",
+                            delay, e
+                        );
+                        break;
+                    }
+                },
+            }
+        }
+    }
+}
+
+/// Remembers the message a part of a reply with feedback
+/// buttons went out in
+fn record_sent(
+    replies: &mut HashMap<String, SentReply>,
+    unsent: &Unsent,
+    sent: MessageOrChannelPost,
+) {
+    if let Some((session, id)) = &unsent.feedback {
+        if let Some(reply) = replies.get_mut(session).filter(|reply| reply.id == *id) {
+            reply.parts.push(sent);
+        }
+    }
+}
+
+async fn send_part(
+    api: &Api,
+    appctl: &AppCtl,
+    unsent: &Unsent,
+    format: TelegramFormat,
+) -> Result<MessageOrChannelPost, TeleError> {
+    let message = &unsent.message;
+    let sent = match &unsent.part {
+        Part::Photo { pic, caption } => {
+            let mut send_this = message.photo_reply(InputFileUpload::with_path(pic.clone()));
+            if let Some(buttons) = &unsent.buttons {
+                send_this.reply_markup(buttons.clone());
+            } else if let MessageChat::Private(_) = message.chat {
+                send_this.reply_markup(get_reply_keyboard(appctl));
+            }
+            if let Some(caption) = caption {
+                send_this.caption(render(caption, format));
+                if let Some(parse_mode) = parse_mode(format) {
+                    send_this.parse_mode(parse_mode);
+                }
+            }
+            api.send(send_this).await?
+        }
+        Part::Text(text) => {
+            let mut send_this = message.text_reply(render(text, format));
+            if let Some(buttons) = &unsent.buttons {
+                send_this.reply_markup(buttons.clone());
+            } else if let MessageChat::Private(_) = message.chat {
+                send_this.reply_markup(get_reply_keyboard(appctl));
+            }
+            if let Some(parse_mode) = parse_mode(format) {
+                send_this.parse_mode(parse_mode);
+            }
+            api.send(send_this).await?
+        }
+    };
+    Ok(sent)
+}
+
+/// Handles a message from telegram without waiting for the bot
+///
+/// Returns false if the bot should shut down
+async fn handle_message(
+    api: &Api,
+    appctl: &AppCtl,
+    message: Message,
+    identity: &Identity,
+    pending: &mut VecDeque<Pending>,
+    outbox: &mut Outbox,
+) -> bool {
+    let data = match message_text(&message.kind) {
+        Some(data) => data,
+        None => return true,
+    };
+    let (session, data) = match identity.conversation(&message, &data) {
+        Some(conversation) => conversation,
+        None => return true,
+    };
+
+    let data = data.trim();
+    let handled = handle(appctl, data);
+    let reply_message = match &handled {
+        Handled::Reply(reply) | Handled::Shutdown(reply) => Some(reply.clone()),
+        Handled::Cancel(reply) => {
+            for waiting in pending
+                .iter_mut()
+                .filter(|waiting| waiting.origin.session == session)
+            {
+                waiting.cancelled = true;
+            }
+            Some(reply.clone())
+        }
+        Handled::Say => {
+            debug!("You: {}", data);
+            let origin = appctl.new_origin(Frontend::Telegram, &session);
+            match appctl.request(Event::Said {
+                origin: origin.clone(),
+                text: data.to_string(),
+            }) {
+                Ok(()) => {
+                    pending.push_back(Pending {
+                        message: message.clone(),
+                        origin,
+                        reply: None,
+                        id: None,
+                        picture: None,
+                        cancelled: false,
+                        action_sent: None,
+                        asked: Instant::now(),
+                    });
+                    None
+                }
+                Err(_) => Some(BUSY_REPLY.to_string()),
+            }
+        }
+    };
+
+    outbox
+        .send(api, appctl, &message, reply_message, None, None)
+        .await;
+    !matches!(handled, Handled::Shutdown(_))
+}
+
+/// Handles a feedback button being pressed under a reply
+async fn handle_callback(
+    api: &Api,
+    appctl: &AppCtl,
+    query: CallbackQuery,
+    identity: &Identity,
+    regenerating: &mut HashMap<u64, Message>,
+) {
+    let (button, session, id) = match query.data.as_deref().and_then(parse_feedback) {
+        Some(feedback) => feedback,
+        None => return,
+    };
+    if !identity.takes_feedback(query.from.id, &session) {
+        return;
+    }
+    let origin = appctl.new_origin(Frontend::Telegram, &session);
+    let answer = match button {
+        Button::Rate(rating) => match appctl.request(Event::Rate { origin, id, rating }) {
+            Ok(()) if rating > 0 => "Glad you liked it",
+            Ok(()) => "Noted",
+            Err(_) => BUSY_REPLY,
+        },
+        Button::Again => {
+            let request = origin.request;
+            match appctl.request(Event::Regenerate { origin, id }) {
+                Ok(()) => {
+                    // Remember which message to edit once the new reply is ready
+                    if let Some(MessageOrChannelPost::Message(message)) = &query.message {
+                        regenerating.insert(request, message.clone());
+                    }
+                    "Thinking again..."
+                }
+                Err(_) => BUSY_REPLY,
+            }
+        }
+    };
+    if let Err(e) = api.send(query.answer(answer)).await {
+        warn!("Telegram: Unable to answer feedback: {:?}", e);
+    }
+}
+
+/// Edits a regenerated reply in place
+///
+/// Parts of the old reply that aren't needed any more are deleted.
+/// If telegram won't edit a part, it and the rest are deleted and
+/// sent again instead
+async fn edit_regenerated(
+    api: &Api,
+    appctl: &AppCtl,
+    message: Message,
+    origin: Origin,
+    id: u64,
+    reply: Option<String>,
+    outbox: &mut Outbox,
+) {
+    let reply = match reply {
+        Some(reply) => reply,
+        None => {
+            let reply_message = "...Only the last reply can be regenerated...".to_string();
+            outbox
+                .send(api, appctl, &message, Some(reply_message), None, None)
+                .await;
+            return;
+        }
+    };
+    let sent = match outbox
+        .replies
+        .remove(&origin.session)
+        .filter(|sent| sent.id == id)
+    {
+        Some(sent) => sent,
+        // Sent before a restart, so only the message with the
+        // buttons is known
+        None => SentReply {
+            id,
+            message: match message.reply_to_message.as_deref() {
+                Some(MessageOrChannelPost::Message(asked)) => asked.clone(),
+                _ => message.clone(),
+            },
+            pic: None,
+            parts: vec![MessageOrChannelPost::Message(message)],
+        },
+    };
+    let photo = match sent.parts.first() {
+        Some(MessageOrChannelPost::Message(first)) => {
+            matches!(first.kind, MessageKind::Photo { .. })
+        }
+        _ => false,
+    };
+    let mut texts = if photo {
+        split_reply(&reply, CAPTION_LIMIT, MESSAGE_LIMIT)
+    } else {
+        split_reply(&reply, MESSAGE_LIMIT, MESSAGE_LIMIT)
+            .into_iter()
+            .filter(|text| !text.is_empty())
+            .collect()
+    };
+
+    let format = outbox.format;
+    let last = texts.len().saturating_sub(1);
+    let mut edited = Vec::new();
+    for (i, (part, text)) in sent.parts.iter().zip(&texts).enumerate() {
+        let buttons = if i == last {
+            Some(feedback_buttons(&origin.session, id))
+        } else {
+            None
+        };
+        let result = if i == 0 && photo {
+            let mut edit_this = EditCaption::new(part, text, format);
+            edit_this.reply_markup = buttons.map(ReplyMarkup::from);
+            api.send(edit_this).await.map(|_| ())
+        } else {
+            let mut edit_this = part.edit_text(render(text, format));
+            if let Some(parse_mode) = parse_mode(format) {
+                edit_this.parse_mode(parse_mode);
+            }
+            if let Some(buttons) = buttons {
+                edit_this.reply_markup(buttons);
+            }
+            api.send(edit_this).await.map(|_| ())
+        };
+        match result {
+            Ok(()) => edited.push(part.clone()),
+            Err(e) if format!("{:?}", e).contains("message is not modified") => {
+                edited.push(part.clone())
+            }
+            Err(e) => {
+                warn!("Telegram: Unable to edit reply, sending it again: {:?}", e);
+                break;
+            }
+        }
+    }
+
+    let kept = edited.len();
+    for part in &sent.parts[kept..] {
+        if let Err(e) = api.send(part.delete()).await {
+            warn!("Telegram: Unable to delete old reply: {:?}", e);
+        }
+    }
+    let parts = if kept == 0 {
+        reply_parts(Some(reply), sent.pic.clone())
+    } else {
+        texts.split_off(kept).into_iter().map(Part::Text).collect()
+    };
+    if !parts.is_empty() {
+        outbox
+            .send_parts(
+                api,
+                appctl,
+                &sent.message,
+                parts,
+                Some((&origin.session, id)),
+            )
+            .await;
+    }
+    let tracked = outbox
+        .replies
+        .entry(origin.session)
+        .or_insert_with(|| SentReply {
+            id,
+            message: sent.message,
+            pic: sent.pic,
+            parts: Vec::new(),
+        });
+    tracked.parts.splice(0..0, edited);
+}
+
+/// Sends any replies the bot has finished and keeps the chat
+/// actions going for those still waiting
+async fn handle_replies(
+    api: &Api,
+    appctl: &AppCtl,
+    bot_name: &str,
+    get_events: &Receiver<Event>,
+    pending: &mut VecDeque<Pending>,
+    regenerating: &mut HashMap<u64, Message>,
+    outbox: &mut Outbox,
+) {
+    outbox.retry(api, appctl).await;
+    while let Ok(event) = get_events.try_recv() {
+        match event {
+            Event::Reply { origin, text, id } => {
+                match pending
+                    .iter_mut()
+                    .find(|waiting| waiting.origin.request == origin.request)
+                {
+                    Some(waiting) => {
+                        debug!("{}: {}", bot_name, text);
+                        waiting.reply = Some(text);
+                        waiting.id = id;
+                        waiting.action_sent = None;
+                    }
+                    None if origin.frontend == Frontend::Telegram => {
+                        debug!("Reply was not asked for by telegram")
+                    }
+                    None => {}
+                }
+            }
+            Event::Picture { origin, path } => {
+                if let Some(waiting) = pending
+                    .iter_mut()
+                    .find(|waiting| waiting.origin.request == origin.request)
+                {
+                    waiting.picture = Some(path);
+                }
+            }
+            Event::Regenerated { origin, id, text } => {
+                if let Some(message) = regenerating.remove(&origin.request) {
+                    edit_regenerated(api, appctl, message, origin, id, text, outbox).await;
+                }
+            }
+            _ => {}
+        }
+    }
+    while let Some(i) = pending
+        .iter()
+        .position(|waiting| waiting.reply.is_some() && waiting.picture.is_some())
+    {
+        let waiting = match pending.remove(i) {
+            Some(waiting) => waiting,
+            None => break,
+        };
+        if waiting.cancelled {
+            debug!("Dropping cancelled reply");
+            continue;
+        }
+        let reply_pic = waiting
+            .picture
+            .flatten()
+            .and_then(|image_path| image_path.into_os_string().into_string().ok());
+        let feedback = waiting.id.map(|id| (waiting.origin.session.as_str(), id));
+        outbox
+            .send(
+                api,
+                appctl,
+                &waiting.message,
+                waiting.reply,
+                reply_pic,
+                feedback,
+            )
+            .await;
+    }
+    // Give up on anything lost on the way, a reply that came
+    // without its picture is sent on its own
+    while let Some(i) = pending
+        .iter()
+        .position(|waiting| waiting.asked.elapsed() > REPLY_TIMEOUT)
+    {
+        let waiting = match pending.remove(i) {
+            Some(waiting) => waiting,
+            None => break,
+        };
+        if waiting.cancelled {
+            continue;
+        }
+        warn!(
+            "Telegram: Gave up waiting on request {}",
+            waiting.origin.request
+        );
+        let feedback = waiting.id.map(|id| (waiting.origin.session.as_str(), id));
+        let reply = waiting.reply.unwrap_or_else(|| TIMEOUT_REPLY.to_string());
+        outbox
+            .send(api, appctl, &waiting.message, Some(reply), None, feedback)
+            .await;
+    }
+    for waiting in pending.iter_mut().filter(|waiting| !waiting.cancelled) {
+        if waiting.reply.is_none() {
+            keep_chat_action(
+                api,
+                &waiting.message.chat,
+                ChatAction::Typing,
+                &mut waiting.action_sent,
+            );
+        } else if appctl.images_enabled() {
+            keep_chat_action(
+                api,
+                &waiting.message.chat,
+                ChatAction::UploadPhoto,
+                &mut waiting.action_sent,
+            );
+        }
+    }
+}
+
+/// Sends the replies still being written as the bot stops, then
+/// tells anyone left waiting that they won't get one
+async fn finish_replies(
+    api: &Api,
+    appctl: &AppCtl,
+    bot_name: &str,
+    get_events: &Receiver<Event>,
+    pending: &mut VecDeque<Pending>,
+    regenerating: &mut HashMap<u64, Message>,
+    outbox: &mut Outbox,
+) {
+    let mut finished = false;
+    while !finished {
+        // Check first so events sent before finishing are still read
+        finished = appctl.is_finished(WORD_IMAGES);
+        handle_replies(
+            api,
+            appctl,
+            bot_name,
+            get_events,
+            pending,
+            regenerating,
+            outbox,
+        )
+        .await;
+        if !finished {
+            tokio::time::delay_for(POLL_INTERVAL).await;
+        }
+    }
+    for waiting in pending.drain(..).filter(|waiting| !waiting.cancelled) {
+        outbox
+            .send(
+                api,
+                appctl,
+                &waiting.message,
+                Some(SHUTDOWN_REPLY.to_string()),
+                None,
+                None,
+            )
+            .await;
+    }
+    if !outbox.unsent.is_empty() {
+        warn!(
+            "Telegram: Leaving with {} parts of replies unsent",
+            outbox.unsent.len()
+        );
+    }
 }
 
 #[allow(clippy::too_many_arguments)]
 pub async fn start_telegram(
-    appctl: &AppCtl,
+    appctl: &Arc<AppCtl>,
     token: &str,
     id: i64,
+    groups: &[i64],
     bot_name: &str,
+    webhook: Option<&WebhookConfig>,
+    max_retries: u32,
+    format: TelegramFormat,
 ) -> Result<(), TeleError> {
-    defer_on_unwind!{ appctl.stop(); }
-    let mut get_from_bot = appctl.listen_bot_channel();
-    let mut get_picture_from_bot = appctl.listen_bot_pic_channel();
+    defer_on_unwind! { appctl.stop(); }
+    let get_events = appctl.listen("telegram");
+    let mut pending: VecDeque<Pending> = Default::default();
+    let mut outbox = Outbox::new(max_retries, format);
+    let mut regenerating: HashMap<u64, Message> = Default::default();
+    let mut commands_registered = false;
+    let mut poll_backoff = Backoff::new(RETRY_BASE, RETRY_MAX);
+    let mut identity = Identity {
+        owner: UserId::new(id as Integer),
+        groups: groups.to_vec(),
+        bot_name: bot_name.to_string(),
+        bot_id: None,
+        username: None,
+    };
+
+    let mut webhook_updates = None;
+    if let Some(webhook) = webhook {
+        match start_webhook(webhook, appctl.clone()) {
+            Ok((_, receiver)) => webhook_updates = Some(receiver),
+            Err(e) => {
+                error!("{}", e);
+                appctl.stop();
+                return Ok(());
+            }
+        }
+        if let Some(url) = &webhook.url {
+            info!("Telegram: Setting webhook to {}", url);
+            if let Err(e) = Api::new(token)
+                .send(SetWebhook::new(url, webhook.secret_token.as_deref()))
+                .await
+            {
+                error!("Telegram: Unable to set webhook: {:?}", e);
+            }
+        }
+    }
 
     while appctl.is_alive() {
         info!("Telegram Starting");
 
         let api = Api::new(token);
+        if !identity.groups.is_empty() {
+            identity.fetch(&api).await;
+        }
+        if !commands_registered {
+            match api.send(SetMyCommands::from_registry()).await {
+                Ok(()) => commands_registered = true,
+                Err(e) => warn!("Telegram: Unable to register commands: {:?}", e),
+            }
+        }
 
         info!("Telegram Started");
 
-        fn get_reply_keyboard(status: &AppCtl) -> ReplyKeyboardMarkup {
-            if status.images_enabled() {
-                reply_markup!(reply_keyboard, selective,
-                     ["/stop"],
-                     ["/noimages"]
-                )
-            } else {
-                reply_markup!(reply_keyboard, selective,
-                     ["/stop"],
-                     ["/yesimages"]
-                )
-            }
-        }
-
-        // Fetch new updates via long poll method
-        let mut stream = api.stream();
-
-        while let Ok(Some(update)) = {
-            debug!("Waiting for new message.");
-            let (abort_handle, abort_registration) = AbortHandle::new_pair();
-            appctl.add_abortable("telegram", abort_handle);
-            let future = Abortable::new(stream.next(), abort_registration);
-            let result = future.await;
-            if let Err(e) = result {
-                error!("Telegram error: {:?}", e);
-            }
-            result
-        } {
-            // If the received update contains a new message...
-            let update = update?;
-            let mut command = TeleCommand::None;
-            if let UpdateKind::Message(message) = update.kind {
-                if let MessageChat::Private(user) = &message.chat  {
-                    if user.id == UserId::new(id as Integer) && message.reply_to_message.is_none() {
-                        if let MessageKind::Text { ref data, .. } = message.kind {
-                            // Print received text message to stdout.
-                            let mut reply_message = None;
-                            let mut reply_pic = None;
-                            match data.trim() {
-                                "/noimages" => {
-                                    reply_message = Some("...Images Off...".to_string());
-                                    command = TeleCommand::Images(false);
-                                },
-                                "/yesimages" => {
-                                    reply_message = Some("...Images On...".to_string());
-                                    command = TeleCommand::Images(true);
-                                },
-                                "/stop" => {
-                                    reply_message = Some("...Leaving...".to_string());
-                                    command = TeleCommand::Shutdown;
-                                },
-                                "/start" => {
-                                    reply_message = Some("Waiting for you to say something".to_string());
-                                },
-                                n if n.starts_with('/') => {
-                                    debug!("Got unknown command from telegram {}", n);
-                                }
-                                n => {
-                                    debug!("You: {}", n.to_string());
-                                    {
-                                        appctl.broadcast_me_channel(n);
-                                    }
-                                    while appctl.is_alive() {
-                                        match get_from_bot.recv_timeout(RX_TIMEOUT) {
-                                            Ok(reply) => {
-                                                debug!("{}: {}", bot_name, reply);
-                                                reply_message = Some(reply);
-                                                break;
-                                            },
-                                            Err(RecvTimeoutError::Disconnected) => {
-                                                appctl.stop();
-                                                error!("Bot communication channel dropped.");
-                                                break;
-                                            }
-                                            Err(RecvTimeoutError::Timeout) => {
-                                                continue;
+        // Fetch new updates via the webhook or long poll method
+        let mut stream = match &mut webhook_updates {
+            Some(receiver) => Updates::Webhook(receiver),
+            None => Updates::Poll(api.stream()),
+        };
+        let mut ticker = tokio::time::interval(POLL_INTERVAL);
+        let (abort_handle, abort_registration) = AbortHandle::new_pair();
+        appctl.add_abortable("telegram", abort_handle);
+
+        let result = Abortable::new(
+            async {
+                while appctl.is_alive() {
+                    // Updates are not read while backing off after an error
+                    tokio::select! {
+                        update = stream.next(), if poll_backoff.ready() => {
+                            match update {
+                                Some(Ok(update)) => {
+                                    poll_backoff.reset();
+                                    // If the received update contains a new message...
+                                    match update.kind {
+                                        UpdateKind::Message(message) => {
+                                            if !handle_message(&api, appctl, message, &identity, &mut pending, &mut outbox).await {
+                                                return false;
                                             }
                                         }
-                                    }
-                                    while appctl.is_alive() {
-                                        match get_picture_from_bot.recv_timeout(RX_TIMEOUT) {
-                                            Ok(image_path) => {
-                                                if let Some(image_path) = image_path {
-                                                    if let Ok(image_path_str) = image_path.into_os_string().into_string() {
-                                                        reply_pic = Some(image_path_str);
-                                                    }
-                                                }
-                                                break;
-                                            },
-                                            Err(RecvTimeoutError::Disconnected) => {
-                                                appctl.stop();
-                                                error!("Bot picture communication channel dropped.");
-                                                break;
-                                            }
-                                            Err(RecvTimeoutError::Timeout) => {
-                                                continue;
-                                            }
+                                        UpdateKind::CallbackQuery(query) => {
+                                            handle_callback(&api, appctl, query, &identity, &mut regenerating).await;
                                         }
+                                        _ => {}
                                     }
                                 }
-                            }
-                            if appctl.is_alive() {
-                                match (reply_message, reply_pic) {
-                                    (Some(reply), Some(pic)) => {
-                                        let mut send_this = message.photo_reply(InputFileUpload::with_path(pic));
-                                        send_this.reply_markup(get_reply_keyboard(appctl));
-                                        send_this.caption(reply);
-                                        api.send(send_this).await?;
-                                    },
-                                    (Some(reply), None) => {
-                                        let mut send_this = message.text_reply(reply);
-                                        send_this.reply_markup(get_reply_keyboard(appctl));
-                                        api.send(send_this).await?;
-                                    },
-                                    (None, Some(pic)) => {
-                                        let mut send_this = message.photo_reply(InputFileUpload::with_path(pic));
-                                        send_this.reply_markup(get_reply_keyboard(appctl));
-                                        api.send(send_this).await?;
-                                    },
-                                    (None, None) => {
+                                Some(Err(e)) => {
+                                    let delay = poll_backoff.fail();
+                                    if poll_backoff.attempts() > max_retries {
+                                        error!("Telegram: Still failing after {} attempts, retrying in {:?}: {:?}", poll_backoff.attempts(), delay, e);
+                                    } else {
+                                        warn!("Telegram error, retrying in {:?}: {:?}", delay, e);
                                     }
                                 }
-                            }
-                            match command {
-                                TeleCommand::None => {},
-                                TeleCommand::Images(yes) => {
-                                    appctl.enable_images(yes);
-                                },
-                                TeleCommand::Shutdown => {
-                                    appctl.stop();
-                                    break;
-                                }
+                                None => break,
                             }
                         }
+                        _ = ticker.tick() => {
+                            handle_replies(
+                                &api,
+                                appctl,
+                                bot_name,
+                                &get_events,
+                                &mut pending,
+                                &mut regenerating,
+                                &mut outbox,
+                            )
+                            .await;
+                        }
                     }
                 }
+                true
+            },
+            abort_registration,
+        )
+        .await;
+
+        match result {
+            Ok(true) => {
+                if appctl.is_alive() {
+                    // The updates ended so wait before reconnecting
+                    let delay = poll_backoff.fail();
+                    warn!("Telegram: Disconnected, reconnecting in {:?}", delay);
+                    tokio::time::delay_for(delay).await;
+                }
+            }
+            Ok(false) => break,
+            Err(_) => {
+                debug!("Telegram: Aborted");
+                break;
             }
         }
     }
     debug!("Telegram: Shutting down");
     appctl.stop();
+    finish_replies(
+        &Api::new(token),
+        appctl,
+        bot_name,
+        &get_events,
+        &mut pending,
+        &mut regenerating,
+        &mut outbox,
+    )
+    .await;
     Ok(())
 }
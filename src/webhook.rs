@@ -0,0 +1,226 @@
+use crate::appctl::AppCtl;
+use crate::command::status_text;
+use crate::config::WebhookConfig;
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde::Serialize;
+use telegram_bot::Update;
+use telegram_bot_raw::{
+    Error as RawError, HttpRequest, JsonRequestType, JsonTrueToUnitResponse,
+    Request as TeleRequest, RequestType, RequestUrl,
+};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use log::*;
+
+/// Header telegram uses to send back the secret token
+const SECRET_HEADER: &str = "X-Telegram-Bot-Api-Secret-Token";
+/// Where `/status` can be fetched from
+const STATUS_PATH: &str = "/status";
+
+/// Tells telegram where to send updates
+#[derive(Serialize, Debug, Clone)]
+pub struct SetWebhook {
+    url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    secret_token: Option<String>,
+}
+
+impl SetWebhook {
+    pub fn new(url: &str, secret_token: Option<&str>) -> Self {
+        Self {
+            url: url.to_string(),
+            secret_token: secret_token.map(|secret_token| secret_token.to_string()),
+        }
+    }
+}
+
+impl TeleRequest for SetWebhook {
+    type Type = JsonRequestType<Self>;
+    type Response = JsonTrueToUnitResponse;
+
+    fn serialize(&self) -> Result<HttpRequest, RawError> {
+        <Self::Type as RequestType>::serialize(RequestUrl::method("setWebhook"), self)
+    }
+}
+
+fn status(code: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = code;
+    response
+}
+
+async fn handle_request(
+    request: Request<Body>,
+    config: Arc<WebhookConfig>,
+    appctl: Arc<AppCtl>,
+    updates: UnboundedSender<Update>,
+) -> Result<Response<Body>, Infallible> {
+    let is_update = request.method() == Method::POST && request.uri().path() == config.path;
+    let is_status = request.method() == Method::GET && request.uri().path() == STATUS_PATH;
+    if !is_update && !is_status {
+        return Ok(status(StatusCode::NOT_FOUND));
+    }
+    // The status is only shown to those who know the secret too
+    if let Some(secret_token) = &config.secret_token {
+        let given_token = request
+            .headers()
+            .get(SECRET_HEADER)
+            .and_then(|value| value.to_str().ok());
+        if given_token != Some(secret_token.as_str()) {
+            warn!("Webhook: Rejected request with a bad secret token");
+            return Ok(status(StatusCode::UNAUTHORIZED));
+        }
+    }
+    if is_status {
+        return Ok(Response::new(Body::from(status_text(&appctl))));
+    }
+    let body = match hyper::body::to_bytes(request.into_body()).await {
+        Ok(body) => body,
+        Err(e) => {
+            warn!("Webhook: Unable to read update: {}", e);
+            return Ok(status(StatusCode::BAD_REQUEST));
+        }
+    };
+    match serde_json::from_slice::<Update>(&body) {
+        Ok(update) => {
+            if updates.send(update).is_err() {
+                Ok(status(StatusCode::SERVICE_UNAVAILABLE))
+            } else {
+                Ok(status(StatusCode::OK))
+            }
+        }
+        Err(e) => {
+            warn!("Webhook: Update is not valid: {}", e);
+            Ok(status(StatusCode::BAD_REQUEST))
+        }
+    }
+}
+
+/// Starts the HTTP listener on the current runtime
+///
+/// Updates posted to the webhook path are sent to the returned receiver
+/// and the bot's status can be fetched from `/status`. Also returns the
+/// address it is bound to, which differs from the config for port 0
+pub fn start_webhook(
+    config: &WebhookConfig,
+    appctl: Arc<AppCtl>,
+) -> Result<(SocketAddr, UnboundedReceiver<Update>), String> {
+    let address: SocketAddr = config
+        .address
+        .parse()
+        .map_err(|e| format!("Webhook: Address {} not valid: {}", config.address, e))?;
+    let (updates, receiver) = unbounded_channel();
+    let config = Arc::new(config.clone());
+
+    let make_service = make_service_fn(move |_| {
+        let config = config.clone();
+        let appctl = appctl.clone();
+        let updates = updates.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                handle_request(request, config.clone(), appctl.clone(), updates.clone())
+            }))
+        }
+    });
+    let server = Server::try_bind(&address)
+        .map_err(|e| format!("Webhook: Unable to listen on {}: {}", address, e))?
+        .serve(make_service);
+    let address = server.local_addr();
+
+    info!("Webhook: Listening on {}", address);
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            error!("Webhook: Server error: {}", e);
+        }
+    });
+    Ok((address, receiver))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "secret";
+    const UPDATE: &str = r#"{
+        "update_id": 1,
+        "message": {
+            "message_id": 1,
+            "date": 0,
+            "from": { "id": 123456, "is_bot": false, "first_name": "Test" },
+            "chat": { "id": 123456, "type": "private", "first_name": "Test" },
+            "text": "Hello, how are you?"
+        }
+    }"#;
+
+    /// Posts the body to the webhook and returns the status with any
+    /// update that was passed on
+    async fn post(body: &str, secret_token: &str) -> (StatusCode, Option<Update>) {
+        let config = Arc::new(WebhookConfig {
+            address: "127.0.0.1:0".to_string(),
+            path: "/telegram".to_string(),
+            secret_token: Some(SECRET.to_string()),
+            url: None,
+        });
+        let appctl = Arc::new(AppCtl::new(1, "alone.toml"));
+        let (updates, mut receiver) = unbounded_channel();
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/telegram")
+            .header(SECRET_HEADER, secret_token)
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = handle_request(request, config, appctl, updates)
+            .await
+            .unwrap();
+        // The sender is dropped so this only waits for an update that was sent
+        (response.status(), receiver.recv().await)
+    }
+
+    #[tokio::test]
+    async fn valid_update_is_passed_on() {
+        let (status, update) = post(UPDATE, SECRET).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(update.map(|update| update.id), Some(1));
+    }
+
+    #[tokio::test]
+    async fn update_posted_to_the_listener_is_passed_on() {
+        let config = WebhookConfig {
+            address: "127.0.0.1:0".to_string(),
+            path: "/telegram".to_string(),
+            secret_token: Some(SECRET.to_string()),
+            url: None,
+        };
+        let appctl = Arc::new(AppCtl::new(1, "alone.toml"));
+        let (address, mut receiver) = start_webhook(&config, appctl).unwrap();
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(format!("http://{}{}", address, config.path))
+            .header(SECRET_HEADER, SECRET)
+            .body(Body::from(UPDATE))
+            .unwrap();
+        let response = hyper::Client::new().request(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(receiver.recv().await.map(|update| update.id), Some(1));
+    }
+
+    #[tokio::test]
+    async fn malformed_update_is_rejected() {
+        let (status, update) = post("{\"not\": \"an update\"", SECRET).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(update.is_none());
+    }
+
+    #[tokio::test]
+    async fn wrong_secret_is_rejected() {
+        let (status, update) = post(UPDATE, "wrong").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(update.is_none());
+    }
+}
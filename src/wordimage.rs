@@ -1,92 +1,498 @@
-use crate::appctl::AppCtl;
+use crate::appctl::{AppCtl, Event, CONVERSATION, WORD_IMAGES};
 use crate::classy::Classy;
-use crate::config::{WordImageData, WordImagesConfig};
+use crate::config::{ImageMatcher, ImageSelection, ImageSource, WordImageData, WordImagesConfig};
+use crate::embed::{cosine_similarity, Embed, EmbeddingCache};
+use crate::models::{model_path, Lazy};
 use crate::RX_TIMEOUT;
 
-use std::collections::HashSet;
-use std::path::PathBuf;
-use std::sync::mpsc::RecvTimeoutError;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 
+use crossbeam_channel::RecvTimeoutError;
+use lru::LruCache;
 use rand::seq::SliceRandom;
-use scopeguard::defer_on_unwind;
+use scopeguard::{defer, defer_on_unwind};
+use serde::{Deserialize, Serialize};
 use validator::Validate;
 
 use log::*;
 
+const STATE_PATH: &str = "./wordimages_state.toml";
+const EMBEDDINGS_PATH: &str = "./wordimages_embeddings.json";
+
+/// The label sets for each image source
+const SOURCES: &[ImageSource] = &[ImageSource::Me, ImageSource::Bot, ImageSource::Both];
+
+/// Scores of every label already classified for a message
+type LabelScores = HashMap<String, f64>;
+
 pub struct WordImage {
-    classy: Classy,
-    word_images: Vec<WordImageData>,
+    embed_model_name: String,
+    classy: Lazy<Classy>,
+    embed: Lazy<Embed>,
+    embeddings: Option<EmbeddingCache>,
+    config: WordImagesConfig,
+    state: ImageState,
+    labels: Vec<(ImageSource, Vec<String>)>,
+    cache: RefCell<LruCache<String, LabelScores>>,
+}
+
+/// Remembers which images were sent and when
+///
+/// A turn is one reply from the bot
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct ImageState {
+    turn: u64,
+    #[serde(default)]
+    last_image_turn: Option<u64>,
+    #[serde(default)]
+    sent: Vec<SentImage>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+struct SentImage {
+    path: PathBuf,
+    turn: u64,
+}
+
+impl ImageState {
+    pub fn load(file_path: &str) -> Self {
+        match std::fs::read_to_string(file_path) {
+            Ok(state_str) => toml::from_str(&state_str).unwrap_or_else(|e| {
+                error!("Wordimages: Error state not readable: {}", e);
+                Default::default()
+            }),
+            Err(_) => Default::default(),
+        }
+    }
+
+    pub fn save(&self, file_path: &str) -> Result<(), String> {
+        std::fs::write(file_path, toml::to_vec(self).unwrap())
+            .map_err(|e| format!("Wordimages: Error state not writable: {}", e))
+    }
+
+    fn turns_since(&self, turn: u64) -> u64 {
+        self.turn.saturating_sub(turn)
+    }
+
+    /// True if the minimum number of turns has passed between
+    /// the last image and this one
+    fn ready(&self, min_turns: usize) -> bool {
+        match self.last_image_turn {
+            Some(last_image_turn) => self.turns_since(last_image_turn) > min_turns as u64,
+            None => true,
+        }
+    }
+
+    /// True if the image was sent within the cooldown or is
+    /// one of the last few images sent
+    fn is_recent(&self, path: &Path, cooldown: usize, avoid_last: usize) -> bool {
+        self.sent.iter().rev().enumerate().any(|(i, sent)| {
+            sent.path == path && (i < avoid_last || self.turns_since(sent.turn) <= cooldown as u64)
+        })
+    }
+
+    fn record(&mut self, path: &Path, avoid_last: usize, max_cooldown: usize) {
+        self.last_image_turn = Some(self.turn);
+        self.sent.retain(|sent| sent.path != path);
+        self.sent.push(SentImage {
+            path: path.to_path_buf(),
+            turn: self.turn,
+        });
+        // Forget images that can no longer affect the choice
+        let turn = self.turn;
+        let keep_from = self.sent.len().saturating_sub(avoid_last.max(1));
+        let mut i = 0;
+        self.sent.retain(|sent| {
+            i += 1;
+            i > keep_from || turn.saturating_sub(sent.turn) <= max_cooldown as u64
+        });
+    }
 }
 
 impl WordImage {
-    pub fn new(model_name: &str, config: &WordImagesConfig) -> Self {
-        Self {
-            classy: Classy::new(model_name),
-            word_images: config.word_images.to_vec(),
+    /// The models are loaded when first needed and
+    /// dropped after sitting idle
+    pub fn new(
+        appctl: &AppCtl,
+        classify_model_name: &str,
+        embed_model_name: &str,
+        idle: Option<Duration>,
+        config: &WordImagesConfig,
+    ) -> Self {
+        let classify_name = classify_model_name.to_string();
+        let embed_name = embed_model_name.to_string();
+        let mut new_self = Self {
+            embed_model_name: embed_model_name.to_string(),
+            classy: Lazy::new(
+                appctl,
+                "zero shot",
+                model_path(classify_model_name),
+                idle,
+                move || Classy::new(&classify_name),
+            ),
+            embed: Lazy::new(
+                appctl,
+                "embeddings",
+                model_path(embed_model_name),
+                idle,
+                move || Embed::new(&embed_name),
+            ),
+            embeddings: None,
+            config: config.clone(),
+            state: ImageState::load(STATE_PATH),
+            labels: vec![],
+            cache: RefCell::new(LruCache::new(cache_size(config))),
+        };
+        new_self.labels = new_self.all_labels();
+        new_self.prepare_matcher();
+        new_self
+    }
+
+    /// Embeds any new words when matching with embeddings
+    fn prepare_matcher(&mut self) {
+        match self.config.matcher {
+            ImageMatcher::ZeroShot => {}
+            ImageMatcher::Embeddings => {
+                let embed_model_name = &self.embed_model_name;
+                let embeddings = self
+                    .embeddings
+                    .get_or_insert_with(|| EmbeddingCache::load(EMBEDDINGS_PATH, embed_model_name));
+                let words: Vec<&str> = self
+                    .labels
+                    .iter()
+                    .flat_map(|(_, words)| words.iter().map(String::as_str))
+                    .collect();
+                if embeddings.extend(&self.embed, &words) {
+                    if let Err(error) = embeddings.save(EMBEDDINGS_PATH) {
+                        error!("{}", error);
+                    }
+                }
+            }
         }
     }
 
-    pub fn new_from_path(model_name: &str, config_path: &str) -> Result<Self, String> {
+    /// Drops the models that have not been used for a while
+    pub fn unload_idle(&self) {
+        self.classy.unload_idle();
+        self.embed.unload_idle();
+    }
+
+    pub fn save_state(&self) -> Result<(), String> {
+        self.state.save(STATE_PATH)
+    }
+
+    pub fn new_from_path(
+        appctl: &AppCtl,
+        classify_model_name: &str,
+        embed_model_name: &str,
+        idle: Option<Duration>,
+        config_path: &str,
+    ) -> Result<Self, String> {
+        let word_config = Self::load_config(config_path)?;
+        Ok(WordImage::new(
+            appctl,
+            classify_model_name,
+            embed_model_name,
+            idle,
+            &word_config,
+        ))
+    }
+
+    /// Reads and validates a word images config without touching the model
+    pub fn load_config(config_path: &str) -> Result<WordImagesConfig, String> {
         match std::fs::read_to_string(config_path) {
             Ok(config_str) => match toml::from_str::<WordImagesConfig>(&config_str) {
-                Ok(word_config) => match word_config.validate() {
-                    Ok(_) => Ok(WordImage::new(model_name, &word_config)),
-                    Err(e) => Err(format!(
-                        "Wordimages: Error not valid WordImagesConfig: {}",
-                        e
-                    )),
-                },
+                Ok(mut word_config) => {
+                    // Found first so each path is only read once
+                    word_config.expand_images();
+                    match word_config.validate() {
+                        Ok(_) => Ok(word_config),
+                        Err(e) => Err(format!(
+                            "Wordimages: Error not valid WordImagesConfig: {}",
+                            e
+                        )),
+                    }
+                }
                 Err(e) => Err(format!("Wordimages: Error not valid toml: {}", e)),
             },
             Err(e) => Err(format!("Wordimages: Error file not readable: {}", e)),
         }
     }
 
-    fn all_words(&self) -> Vec<String> {
+    /// Swaps in a new config keeping the loaded model
+    ///
+    /// The other model is only loaded if the matcher was changed
+    pub fn set_config(&mut self, config: WordImagesConfig) {
+        // Scores depend on the template so they cannot be reused
+        self.cache = RefCell::new(LruCache::new(cache_size(&config)));
+        self.config = config;
+        self.labels = self.all_labels();
+        self.prepare_matcher();
+    }
+
+    fn all_labels(&self) -> Vec<(ImageSource, Vec<String>)> {
+        SOURCES
+            .iter()
+            .map(|source| (*source, self.all_words(*source)))
+            .filter(|(_, words)| !words.is_empty())
+            .collect()
+    }
+
+    fn source(&self, word_image: &WordImageData) -> ImageSource {
+        word_image.trigger.unwrap_or(self.config.classify)
+    }
+
+    fn all_words(&self, source: ImageSource) -> Vec<String> {
         let temp_vec: Vec<String> = self
+            .config
             .word_images
             .iter()
+            .filter(|i| self.source(i) == source)
             .flat_map(|i| i.words.to_vec())
             .collect();
         let temp_hash: HashSet<String> = temp_vec.into_iter().collect();
         temp_hash.into_iter().collect()
     }
 
-    pub fn get_image_path(&self, input: &str) -> Option<PathBuf> {
-        let words_owd = self.all_words();
-        let words: Vec<_> = words_owd.iter().map(String::as_str).collect();
-        if let Some(labels) = self.classy.classify_with_lables(input, &words) {
-            let strong_labels: Vec<_> = labels.iter().filter(|i| i.score > 0.96).collect();
-            let target_label = strong_labels.choose(&mut rand::thread_rng());
-            if let Some(target_label) = target_label {
-                let valid_word_images: Vec<_> = self
-                    .word_images
-                    .iter()
-                    .filter(|i| i.words.contains(&target_label.text))
-                    .collect();
-                let target_word_image = valid_word_images.choose(&mut rand::thread_rng());
-                if let Some(target_word_image) = target_word_image {
-                    return Some(target_word_image.path.clone());
+    fn threshold(&self, word_image: &WordImageData) -> f64 {
+        word_image.threshold.unwrap_or(match self.config.matcher {
+            ImageMatcher::ZeroShot => self.config.threshold,
+            ImageMatcher::Embeddings => self.config.similarity_threshold,
+        })
+    }
+
+    fn is_strong(&self, word_image: &WordImageData, score: f64) -> bool {
+        score > self.threshold(word_image)
+    }
+
+    fn cooldown(&self, word_image: &WordImageData) -> usize {
+        word_image.cooldown.unwrap_or(self.config.image_cooldown)
+    }
+
+    fn max_cooldown(&self) -> usize {
+        self.config
+            .word_images
+            .iter()
+            .map(|i| self.cooldown(i))
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// The images of a word image that are not cooling down
+    fn fresh_images<'a>(&self, word_image: &'a WordImageData) -> Vec<&'a PathBuf> {
+        let cooldown = self.cooldown(word_image);
+        word_image
+            .images
+            .iter()
+            .filter(|path| !self.state.is_recent(path, cooldown, self.config.avoid_last))
+            .collect()
+    }
+
+    /// Scores the labels against the input
+    ///
+    /// Only labels that are not in the cache for this input
+    /// are sent to the model
+    fn label_scores(&self, input: &str, labels: &[String]) -> LabelScores {
+        let mut cache = self.cache.borrow_mut();
+        let mut scores = cache.get(input).cloned().unwrap_or_default();
+        let missing: Vec<&str> = labels
+            .iter()
+            .filter(|label| !scores.contains_key(label.as_str()))
+            .map(String::as_str)
+            .collect();
+        if !missing.is_empty() {
+            trace!("Wordimages: Classifying {} labels", missing.len());
+            scores.extend(self.score_labels(input, &missing));
+            cache.put(input.to_string(), scores.clone());
+        }
+        scores
+    }
+
+    fn score_labels(&self, input: &str, labels: &[&str]) -> LabelScores {
+        match self.config.matcher {
+            ImageMatcher::ZeroShot => self.classy.with(|classy| {
+                classy
+                    .classify_in_chunks(
+                        input,
+                        labels,
+                        self.config.hypothesis_template.as_deref(),
+                        self.config.max_length,
+                        self.config.label_batch,
+                    )
+                    .into_iter()
+                    .map(|label| (label.text, label.score))
+                    .collect()
+            }),
+            ImageMatcher::Embeddings => match &self.embeddings {
+                Some(embeddings) => {
+                    let input_embedding = self
+                        .embed
+                        .with(|embed| embed.embed(&[input]))
+                        .and_then(|mut e| e.pop());
+                    match input_embedding {
+                        Some(input_embedding) => labels
+                            .iter()
+                            .filter_map(|label| {
+                                embeddings.get(label).map(|label_embedding| {
+                                    (
+                                        label.to_string(),
+                                        cosine_similarity(&input_embedding, label_embedding),
+                                    )
+                                })
+                            })
+                            .collect(),
+                        None => Default::default(),
+                    }
+                }
+                None => Default::default(),
+            },
+        }
+    }
+
+    /// Finds every word image with a strong label in the text
+    /// of the side of the exchange that triggers it
+    fn matches(&self, me: &str, bot: &str) -> Vec<(&WordImageData, &str, f64)> {
+        let mut matches = vec![];
+        for (source, labels) in self.labels.iter() {
+            let input = match source {
+                ImageSource::Me => me.to_string(),
+                ImageSource::Bot => bot.to_string(),
+                ImageSource::Both => format!("{}\n{}", me, bot),
+            };
+            if input.trim().is_empty() {
+                continue;
+            }
+            let scores = self.label_scores(&input, labels);
+            for word_image in self.config.word_images.iter() {
+                if self.source(word_image) != *source || self.fresh_images(word_image).is_empty() {
+                    continue;
+                }
+                for word in word_image.words.iter() {
+                    if let Some(score) = scores.get(word) {
+                        if self.is_strong(word_image, *score) {
+                            matches.push((word_image, word.as_str(), *score));
+                        }
+                    }
+                }
+            }
+        }
+        matches
+    }
+
+    /// Picks an image for an exchange of my input and the bot reply
+    ///
+    /// Each call counts as a turn for the cooldowns
+    pub fn get_image_path(&mut self, me: &str, bot: &str) -> Option<PathBuf> {
+        self.state.turn += 1;
+        if !self.state.ready(self.config.min_turns) {
+            debug!("Wordimages: Cooling down");
+            return None;
+        }
+        let path = self.choose_image_path(me, bot);
+        if let Some(path) = &path {
+            let max_cooldown = self.max_cooldown();
+            self.state
+                .record(path, self.config.avoid_last, max_cooldown);
+        }
+        path
+    }
+
+    /// Each matching word image with its best label score
+    /// multiplied by its weight
+    fn candidates(&self, me: &str, bot: &str) -> Vec<(&WordImageData, f64)> {
+        let mut candidates: Vec<(&WordImageData, f64)> = vec![];
+        for (word_image, _, score) in self.matches(me, bot) {
+            let weighted_score = score.max(0.0) * word_image.weight;
+            match candidates
+                .iter_mut()
+                .find(|(candidate, _)| std::ptr::eq(*candidate, word_image))
+            {
+                Some((_, best_score)) => {
+                    if weighted_score > *best_score {
+                        *best_score = weighted_score;
+                    }
                 }
+                None => candidates.push((word_image, weighted_score)),
+            }
+        }
+        candidates
+    }
+
+    fn choose_image_path(&self, me: &str, bot: &str) -> Option<PathBuf> {
+        let candidates = self.candidates(me, bot);
+        let target_word_image = match self.config.selection {
+            ImageSelection::Weighted => candidates
+                .choose_weighted(&mut rand::thread_rng(), |(_, score)| *score)
+                .ok(),
+            ImageSelection::Best => candidates.iter().fold(None, |best, candidate| match best {
+                Some((_, best_score)) if *best_score >= candidate.1 => best,
+                _ => Some(candidate),
+            }),
+        };
+        if let Some((target_word_image, score)) = target_word_image {
+            debug!(
+                "Wordimages: Chose {:?} with weighted score {}",
+                target_word_image.path, score
+            );
+            let images = self.fresh_images(target_word_image);
+            match self.config.selection {
+                ImageSelection::Weighted => images
+                    .choose(&mut rand::thread_rng())
+                    .map(|path| path.to_path_buf()),
+                ImageSelection::Best => images.first().map(|path| path.to_path_buf()),
             }
+        } else {
+            None
         }
-        None
     }
 }
 
-pub fn start_wordimages(appctl: &AppCtl, model_name: &str, config_path: Option<String>) {
+fn cache_size(config: &WordImagesConfig) -> NonZeroUsize {
+    NonZeroUsize::new(config.cache_size).unwrap_or(NonZeroUsize::new(1).unwrap())
+}
+
+fn modified_time(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+pub fn start_wordimages(
+    appctl: &AppCtl,
+    classify_model_name: &str,
+    embed_model_name: &str,
+    config_path: Option<String>,
+    idle: Option<Duration>,
+) {
     defer_on_unwind! { appctl.stop() }
-    let mut get_from_bot = appctl.listen_bot_channel();
+    defer! { appctl.finish(WORD_IMAGES) }
+    let get_events = appctl.serve(WORD_IMAGES);
     debug!("Wordimages: Loading");
 
     let mut wordy: Option<WordImage> = None;
+    // What was said in each request still waiting on its reply
+    let mut inputs: HashMap<u64, String> = HashMap::new();
+    let mut config_modified: Option<SystemTime> = None;
 
-    while appctl.is_alive() {
-        if appctl.images_enabled() && wordy.is_none() {
+    // Replies keep coming until the conversation has finished
+    while appctl.is_alive() || !appctl.is_finished(CONVERSATION) || !get_events.is_empty() {
+        if appctl.is_alive() && appctl.images_enabled() && wordy.is_none() {
             // Only bother loading if enabled
             if let Some(config_path) = &config_path {
-                match WordImage::new_from_path(model_name, config_path) {
+                config_modified = modified_time(config_path);
+                match WordImage::new_from_path(
+                    appctl,
+                    classify_model_name,
+                    embed_model_name,
+                    idle,
+                    config_path,
+                ) {
                     Ok(new_wordy) => {
+                        debug!("Wordimages: Ready");
                         wordy = Some(new_wordy);
                     }
                     Err(error) => {
@@ -96,50 +502,134 @@ pub fn start_wordimages(appctl: &AppCtl, model_name: &str, config_path: Option<S
             }
         }
 
-        if let Some(wordy) = &wordy {
-            // Its been loaded
-            debug!("Wordimages: Ready");
-
-            match get_from_bot.recv_timeout(RX_TIMEOUT) {
-                // Picture asked for
-                Ok(input) => {
-                    if appctl.images_enabled() {
-                        // Find and send it
-                        appctl.broadcast_bot_pic_channel(wordy.get_image_path(&input));
-                    } else {
-                        // But we have been turned off
-                        appctl.broadcast_bot_pic_channel(None);
+        if let (Some(wordy), Some(config_path)) = (&mut wordy, &config_path) {
+            let new_modified = modified_time(config_path);
+            if new_modified != config_modified {
+                // Only try each change once, a broken file is
+                // retried after it is next saved
+                config_modified = new_modified;
+                match WordImage::load_config(config_path) {
+                    Ok(new_config) => {
+                        info!("Wordimages: Reloaded {}", config_path);
+                        wordy.set_config(new_config);
+                    }
+                    Err(error) => {
+                        error!("{}", error);
+                        warn!("Wordimages: Keeping the previous config");
                     }
-                }
-                Err(RecvTimeoutError::Disconnected) => {
-                    appctl.stop();
-                    error!("Bot communication channel dropped.");
-                    break;
-                }
-                Err(RecvTimeoutError::Timeout) => {
-                    continue;
                 }
             }
+        }
 
-            debug!("Wordimages: Shutting down");
-        } else {
-            // Never loaded
-            match get_from_bot.recv_timeout(RX_TIMEOUT) {
-                // Picture asked for
-                Ok(_) => {
-                    appctl.broadcast_bot_pic_channel(None); // But we are not loaded
-                }
-                Err(RecvTimeoutError::Disconnected) => {
-                    appctl.stop();
-                    error!("Bot communication channel dropped.");
-                    break;
-                }
-                Err(RecvTimeoutError::Timeout) => {
-                    continue;
+        match get_events.recv_timeout(RX_TIMEOUT) {
+            // Keep up with what I said so the exchange can be classified
+            Ok(Event::Said { origin, text }) => {
+                inputs.insert(origin.request, text);
+            }
+            // Picture asked for
+            Ok(Event::Reply { origin, text, .. }) => {
+                let input = inputs.remove(&origin.request).unwrap_or_default();
+                let path = match &mut wordy {
+                    Some(wordy) if appctl.images_enabled() => {
+                        // Find and send it
+                        let image_path = wordy.get_image_path(&input, &text);
+                        if let Err(error) = wordy.save_state() {
+                            error!("{}", error);
+                        }
+                        image_path
+                    }
+                    // Not loaded or turned off
+                    _ => None,
+                };
+                appctl.broadcast(Event::Picture { origin, path });
+            }
+            Ok(_) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                appctl.stop();
+                error!("Bot communication channel dropped.");
+                break;
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                if let Some(wordy) = &wordy {
+                    wordy.unload_idle();
                 }
+                continue;
             }
         }
     }
 
+    debug!("Wordimages: Shutting down");
+    if let Some(wordy) = &wordy {
+        if let Err(error) = wordy.save_state() {
+            error!("{}", error);
+        }
+    }
     appctl.stop();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Moves on a turn and sends the image if there is one
+    fn reply(state: &mut ImageState, image: Option<&str>, avoid_last: usize, max_cooldown: usize) {
+        state.turn += 1;
+        if let Some(image) = image {
+            state.record(Path::new(image), avoid_last, max_cooldown);
+        }
+    }
+
+    #[test]
+    fn min_turns_must_pass_between_images() {
+        let mut state = ImageState::default();
+        assert!(state.ready(1));
+        reply(&mut state, Some("a.jpg"), 0, 0);
+        reply(&mut state, None, 0, 0);
+        assert!(state.ready(0));
+        assert!(!state.ready(1));
+        reply(&mut state, None, 0, 0);
+        assert!(state.ready(1));
+        assert!(!state.ready(2));
+    }
+
+    #[test]
+    fn cooldown_must_pass_before_an_image_repeats() {
+        let mut state = ImageState::default();
+        reply(&mut state, Some("a.jpg"), 0, 2);
+        reply(&mut state, None, 0, 2);
+        assert!(!state.is_recent(Path::new("a.jpg"), 0, 0));
+        assert!(state.is_recent(Path::new("a.jpg"), 1, 0));
+        assert!(state.is_recent(Path::new("a.jpg"), 2, 0));
+        reply(&mut state, None, 0, 2);
+        assert!(!state.is_recent(Path::new("a.jpg"), 1, 0));
+        assert!(state.is_recent(Path::new("a.jpg"), 2, 0));
+        reply(&mut state, None, 0, 2);
+        assert!(!state.is_recent(Path::new("a.jpg"), 2, 0));
+        assert!(!state.is_recent(Path::new("b.jpg"), 2, 0));
+    }
+
+    #[test]
+    fn last_images_are_avoided() {
+        let mut state = ImageState::default();
+        for image in &["a.jpg", "b.jpg", "c.jpg"] {
+            reply(&mut state, Some(image), 2, 0);
+        }
+        reply(&mut state, None, 2, 0);
+        assert!(!state.is_recent(Path::new("a.jpg"), 0, 2));
+        assert!(state.is_recent(Path::new("b.jpg"), 0, 2));
+        assert!(state.is_recent(Path::new("c.jpg"), 0, 2));
+        assert!(!state.is_recent(Path::new("b.jpg"), 0, 1));
+    }
+
+    #[test]
+    fn record_forgets_images_that_no_longer_matter() {
+        let mut state = ImageState::default();
+        reply(&mut state, Some("a.jpg"), 1, 1);
+        reply(&mut state, Some("b.jpg"), 1, 1);
+        assert_eq!(state.sent.len(), 2);
+        reply(&mut state, None, 1, 1);
+        reply(&mut state, Some("c.jpg"), 1, 1);
+        let kept: Vec<&Path> = state.sent.iter().map(|sent| sent.path.as_path()).collect();
+        assert_eq!(kept, vec![Path::new("c.jpg")]);
+    }
+}